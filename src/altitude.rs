@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use bevy::{
+    prelude::*,
+};
+
+use crate::velocitate;
+use velocitate::*;
+use crate::steering;
+use steering::*;
+use crate::boids;
+use boids::*;
+use crate::flight;
+use flight::*;
+use crate::terrain;
+use terrain::*;
+
+// Our own plugin. Anything with KeepAltitude is steered softly back into its
+// altitude band, measured from the HeightField's ground (or y = 0 without one).
+// Velocitators get it as a steering behaviour; Flyers not steered by a
+// Velocitator have their goal pitch nudged instead.
+pub struct JayAltitude;
+
+impl Plugin for JayAltitude {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<AltitudeBand>()
+            .init_resource::<SpeciesAltitudeBands>()
+            .add_system(keep_altitude_system.label(SteeringSystem::Behaviours))
+            .add_system(flyer_altitude_system
+                .after(FlightSystem::Goals)
+                .before(FlightSystem::Steering));
+    }
+}
+
+// Heights above the ground we like to stay between. Beyond them we're pushed
+// back, harder the further out we are, up to full strength `softness` outside.
+// The resource is the global default; the same thing as a component
+// overrides it for one entity. Species defaults sit in between.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct AltitudeBand {
+    pub min: f32,
+    pub max: f32,
+    pub softness: f32,
+}
+
+impl Default for AltitudeBand {
+    fn default() -> Self {
+        AltitudeBand {
+            min: 15.0,
+            max: 300.0,
+            softness: 20.0,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SpeciesAltitudeBands(pub HashMap<Species, AltitudeBand>);
+
+fn resolve_band<'a>(
+    global: &'a AltitudeBand,
+    per_species: &'a SpeciesAltitudeBands,
+    own: Option<&'a AltitudeBand>,
+    species: Option<&Species>,
+) -> &'a AltitudeBand
+{
+    if let Some(own) = own { return own; }
+
+    species
+        .and_then(|species| per_species.0.get(species))
+        .unwrap_or(global)
+}
+
+/// How hard to climb (positive) or dive (negative), from -1 to 1, to get back into the band.
+pub fn altitude_push(height: f32, band: &AltitudeBand) -> f32
+{
+    let outside = if height < band.min {
+        band.min - height
+    } else if height > band.max {
+        band.max - height
+    } else {
+        return 0.0;
+    };
+
+    if band.softness <= 0. { return outside.signum(); }
+    (outside / band.softness).clamp(-1.0, 1.0)
+}
+
+fn height_above_ground(ground: &Option<Res<HeightField>>, pos: Vec3) -> f32
+{
+    match ground {
+        Some(ground) => pos.y - ground.height_at(pos.x, pos.z),
+        None => pos.y,
+    }
+}
+
+// Opt in to altitude keeping.
+#[derive(Component, Debug)]
+pub struct KeepAltitude {
+    pub weight: f32,
+    pub altitude_factor: Vec3,
+}
+
+impl Default for KeepAltitude {
+    fn default() -> Self {
+        KeepAltitude {
+            weight: 1.0,
+            altitude_factor: Vec3::ZERO,
+        }
+    }
+}
+
+fn keep_altitude_system(
+    global: Res<AltitudeBand>,
+    per_species: Res<SpeciesAltitudeBands>,
+    ground: Option<Res<HeightField>>,
+    mut query: Query<(&mut KeepAltitude, &Transform, &Velocitator, Option<&AltitudeBand>, Option<&Species>)>,
+)
+{
+    for (mut keep, transform, velocitator, own, species) in query.iter_mut() {
+        let band = resolve_band(&global, &per_species, own, species);
+        let push = altitude_push(height_above_ground(&ground, transform.translation), band);
+
+        // Aim for a climb or dive rate, so we level off rather than bounce.
+        keep.altitude_factor = if push != 0. {
+            Vec3::Y * (push * velocitator.max_speed - velocitator.velocity.y)
+        } else {
+            Vec3::ZERO
+        };
+    }
+}
+
+fn flyer_altitude_system(
+    global: Res<AltitudeBand>,
+    per_species: Res<SpeciesAltitudeBands>,
+    ground: Option<Res<HeightField>>,
+    mut query: Query<(&KeepAltitude, &Transform, &mut FlyerGoalComponents, Option<&AltitudeBand>, Option<&Species>), (With<Flyer>, Without<SteersFlyer>)>,
+)
+{
+    for (keep, transform, mut goal, own, species) in query.iter_mut() {
+        let band = resolve_band(&global, &per_species, own, species);
+        let push = altitude_push(height_above_ground(&ground, transform.translation), band);
+        if push == 0. { continue; }
+
+        // Tilt the goal direction's climb, short of straight up or down.
+        let climb = (goal.ang_x.sin() + push * keep.weight).clamp(-0.9, 0.9);
+        goal.ang_x = climb.asin();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_is_soft_outside_the_band() {
+        let band = AltitudeBand { min: 20.0, max: 100.0, softness: 10.0 };
+        assert_eq!(altitude_push(50.0, &band), 0.0);
+        assert_eq!(altitude_push(15.0, &band), 0.5);
+        assert_eq!(altitude_push(0.0, &band), 1.0);
+        assert_eq!(altitude_push(105.0, &band), -0.5);
+        assert_eq!(altitude_push(500.0, &band), -1.0);
+    }
+
+    #[test]
+    fn bands_resolve_own_then_species_then_global() {
+        let global = AltitudeBand::default();
+        let mut per_species = SpeciesAltitudeBands::default();
+        per_species.0.insert(Species(1), AltitudeBand { min: 1.0, max: 2.0, softness: 1.0 });
+        let own = AltitudeBand { min: 5.0, max: 6.0, softness: 1.0 };
+
+        assert_eq!(resolve_band(&global, &per_species, Some(&own), Some(&Species(1))).min, 5.0);
+        assert_eq!(resolve_band(&global, &per_species, None, Some(&Species(1))).min, 1.0);
+        assert_eq!(resolve_band(&global, &per_species, None, Some(&Species(2))).min, global.min);
+    }
+}
@@ -0,0 +1,166 @@
+use bevy::{
+    prelude::*,
+};
+use heron::{
+    rapier_plugin::PhysicsWorld,
+    CollisionLayers,
+};
+
+use crate::velocitate;
+use velocitate::*;
+use crate::steering;
+use steering::*;
+use crate::terrain;
+use terrain::*;
+
+// Our own plugin. Steers away from heron colliders by probing ahead with rays,
+// and away from the ground using the HeightField if there is one (so give a
+// ground collider ProbesIgnore, or it's avoided twice). Needs heron's PhysicsPlugin.
+pub struct JayColliderAvoidance;
+
+impl Plugin for JayColliderAvoidance {
+    fn build(&self, app: &mut App) {
+        app
+            .add_system(collider_avoid_system.label(SteeringSystem::Behaviours));
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct ColliderAvoid {
+    // How far ahead the middle probe reaches; the side probes reach a bit less.
+    pub look_ahead: f32,
+    // How far the side probes splay out from straight ahead, in radians.
+    pub probe_spread: f32,
+    // How high above the ground we'd like to stay.
+    pub ground_clearance: f32,
+    pub weight: f32,
+    pub collider_avoid_factor: Vec3,
+}
+
+impl Default for ColliderAvoid {
+    fn default() -> Self {
+        ColliderAvoid {
+            look_ahead: 40.0,
+            probe_spread: 0.4,
+            ground_clearance: 15.0,
+            weight: 1.0,
+            collider_avoid_factor: Vec3::ZERO,
+        }
+    }
+}
+
+// Colliders the probes don't see, like ground the HeightField already keeps us off.
+#[derive(Component, Debug, Default)]
+pub struct ProbesIgnore;
+
+// Straight ahead, then left, right, up and down of it.
+fn probes(heading: Vec3, spread: f32) -> [Vec3; 5]
+{
+    let side = heading.cross(Vec3::Y);
+    let side = if side.length_squared() > 0.0001 { side.normalize() } else { heading.cross(Vec3::X).normalize() };
+    let up = side.cross(heading);
+    let tilt = spread.tan();
+    [
+        heading,
+        (heading - side * tilt).normalize(),
+        (heading + side * tilt).normalize(),
+        (heading + up * tilt).normalize(),
+        (heading - up * tilt).normalize(),
+    ]
+}
+
+// Turn away from a surface we're heading for, the closer the harder.
+// Hitting it square on, there's no sideways to turn to so pick the probe's side.
+fn away_from_hit(heading: Vec3, probe: Vec3, normal: Vec3, urgency: f32, max_speed: f32) -> Vec3
+{
+    let mut sideways = normal - heading * normal.dot(heading);
+    if sideways.length_squared() < 0.0001 {
+        sideways = heading - probe;
+    }
+    if sideways.length_squared() < 0.0001 {
+        sideways = heading.cross(Vec3::Y);
+    }
+    sideways.normalize_or_zero() * max_speed * urgency
+}
+
+/// Push upwards when we're, or soon will be, closer to the ground than `clearance`.
+pub fn ground_avoid(pos: Vec3, velocity: Vec3, ground: &HeightField, look_ahead: f32, clearance: f32, max_speed: f32) -> Vec3
+{
+    if clearance <= 0. { return Vec3::ZERO; }
+
+    let ahead = pos + velocity.normalize_or_zero() * look_ahead;
+    let height = (pos.y - ground.height_at(pos.x, pos.z))
+        .min(ahead.y - ground.height_at(ahead.x, ahead.z));
+    if height >= clearance { return Vec3::ZERO; }
+
+    Vec3::Y * max_speed * (1.0 - height / clearance).min(2.0)
+}
+
+fn collider_avoid_system(
+    physics_world: PhysicsWorld,
+    ground: Option<Res<HeightField>>,
+    mut query: Query<(&mut ColliderAvoid, &Transform, &Velocitator, Entity)>,
+    ignored: Query<(), With<ProbesIgnore>>,
+)
+{
+    for (mut avoid_us, transform, velocitator, entity) in query.iter_mut() {
+        let seen = |hit: Entity| hit != entity && ignored.get(hit).is_err();
+
+        let pos = transform.translation;
+        let heading = velocitator.velocity.normalize_or_zero();
+
+        let mut factor = Vec3::ZERO;
+        if heading != Vec3::ZERO {
+            for (i, probe) in probes(heading, avoid_us.probe_spread).iter().enumerate() {
+                let reach = if i == 0 { avoid_us.look_ahead } else { avoid_us.look_ahead * 0.6 };
+                if let Some(hit) = physics_world.ray_cast_with_filter(pos, *probe * reach, true, CollisionLayers::default(), seen) {
+                    let urgency = 1.0 - ((hit.collision_point - pos).length() / reach).clamp(0.0, 1.0);
+                    factor += away_from_hit(heading, *probe, hit.normal, urgency, velocitator.max_speed);
+                }
+            }
+        }
+
+        if let Some(ground) = &ground {
+            factor += ground_avoid(pos, velocitator.velocity, ground, avoid_us.look_ahead, avoid_us.ground_clearance, velocitator.max_speed);
+        }
+
+        avoid_us.collider_avoid_factor = factor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bounds::Bounds;
+
+    fn ground() -> HeightField {
+        HeightField::flat(&Bounds::new(10., 0., 100., 0., 50., 0., 100., 0.), 0.0)
+    }
+
+    #[test]
+    fn ground_pushes_up_when_low() {
+        let push = ground_avoid(Vec3::new(50.0, 5.0, 50.0), Vec3::new(10.0, 0.0, 0.0), &ground(), 20.0, 10.0, 10.0);
+        assert_eq!(push, Vec3::new(0.0, 5.0, 0.0));
+
+        let none = ground_avoid(Vec3::new(50.0, 30.0, 50.0), Vec3::new(10.0, 0.0, 0.0), &ground(), 20.0, 10.0, 10.0);
+        assert_eq!(none, Vec3::ZERO);
+    }
+
+    #[test]
+    fn ground_pushes_up_when_diving() {
+        let push = ground_avoid(Vec3::new(50.0, 15.0, 50.0), Vec3::new(0.0, -10.0, 0.0), &ground(), 20.0, 10.0, 10.0);
+        assert!(push.y > 0.0);
+    }
+
+    #[test]
+    fn head_on_hits_still_turn() {
+        let heading = Vec3::new(0.0, 0.0, -1.0);
+        let turn = away_from_hit(heading, heading, Vec3::Z, 1.0, 10.0);
+        assert!((turn.length() - 10.0).abs() < 1e-4);
+        assert!(turn.dot(heading).abs() < 1e-4);
+
+        let probes = probes(heading, 0.4);
+        let left = away_from_hit(heading, probes[1], Vec3::Z, 1.0, 10.0);
+        assert!(left.dot(probes[1] - heading) < 0.0);
+    }
+}
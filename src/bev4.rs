@@ -5,18 +5,41 @@ use crate::boids;
 use boids::*;
 use crate::observe;
 use observe::*;
+use crate::spatial;
+use spatial::*;
+use crate::occlusion;
+use occlusion::*;
+use crate::density;
+use density::*;
 use crate::velocitate;
 use velocitate::*;
+use crate::steering;
+use steering::*;
+use crate::avoidance;
+use avoidance::*;
+use crate::terrain;
+use terrain::*;
+use crate::predation;
+use predation::*;
+use crate::metrics;
+use metrics::*;
+use crate::wind;
+use wind::*;
+use crate::altitude;
+use altitude::*;
 use crate::bounds;
 use bounds::*;
 use crate::flight;
 use flight::*;
+use crate::jaymath;
 
 use bevy::{
     prelude::*,
 };
 use bevy_editor_pls::prelude::*;
+use bevy_inspector_egui::InspectorPlugin;
 use big_brain::prelude::*;
+use heron::prelude::*;
 use rand::prelude::*;
 
 use smooth_bevy_cameras::{
@@ -24,6 +47,17 @@ use smooth_bevy_cameras::{
     controllers::fps::{FpsCameraBundle, FpsCameraController, FpsCameraPlugin},
 };
 
+// Observation layers.
+const LAYER_CROWS: u32 = 1 << 0;
+const LAYER_RAPTORS: u32 = 1 << 1;
+
+// Fly the crows with the Flight model, steered by the boids, rather than
+// moving them straight along their Velocitator.
+const CROWS_USE_FLIGHT_MODEL: bool = true;
+
+const SPECIES_CROW: Species = Species(0);
+const SPECIES_MYNA: Species = Species(1);
+
 pub fn start_bevy() {
     
     // The overall bounds of our simulation.
@@ -43,21 +77,51 @@ pub fn start_bevy() {
         .add_plugin(EditorPlugin) // bevy_editor_pls, press E!
         .add_plugin(BigBrainPlugin)
         .add_plugin(JayAnimation)
-        .add_plugin(JayObserve)
+        .add_plugin(JayObserve {
+            backend: IndexBackend::sparse_grid_for(&dem_bounds),
+        })
+        .add_plugin(PhysicsPlugin::default())
+        .add_plugin(JayOcclusion)
+        .add_plugin(JayDensity)
+        .insert_resource(DensityExport {
+            path: Some("crow_density.csv".into()),
+            ..default()
+        })
         .add_plugin(JayBoids)
+        .insert_resource(mixed_flock_affinities())
+        .add_plugin(JaySteering)
+        .add_plugin(JayColliderAvoidance)
+        .add_plugin(JayPredation)
+        .add_plugin(JayMetrics)
+        .add_plugin(JayWind)
+        .add_plugin(JayAltitude)
+        .insert_resource(species_altitude_bands())
+        .insert_resource(Wind {
+            global: Vec3::new(6.0, 0.0, 2.0),
+            turbulence_strength: 4.0,
+            turbulence_scale: 300.0,
+            gust_strength: 12.0,
+            ..default()
+        })
+        .add_plugin(InspectorPlugin::<FlockMetrics>::new())
+        .add_plugin(InspectorPlugin::<FlockingProfile>::new())
         .add_plugin(Flight)
+        // Or FlightModel::Aerodynamic, to fly on lift and drag; either can be picked in the inspector.
+        .insert_resource(FlightModel::Kinematic)
+        .add_plugin(InspectorPlugin::<FlightModel>::new())
         .add_plugin(JayVelocitate)
         .insert_resource(AmbientLight {
             color: Color::WHITE,
             brightness: 1.0,
         })
-        .insert_resource(StuffsToObserve::new(dem_bounds.cells_x, dem_bounds.cells_z, dem_bounds.cell_size))
         .insert_resource(ClearColor(Color::rgb(1.0, 0.8, 0.2)))
+        .insert_resource(HeightField::flat(&dem_bounds, 0.0))
         .insert_resource(dem_bounds)
         .add_plugin(LookTransformPlugin)
         .add_plugin(FpsCameraPlugin::default())
         .add_startup_system(startup)
         .add_system(ever_building_excitement_system)
+        .add_system(crow_captured_system)
         .add_system_to_stage(BigBrainStage::Actions, burn_energy_action_system)
         .add_system_to_stage(BigBrainStage::Scorers, cannot_even_scorer_system)
         .run();
@@ -138,17 +202,52 @@ fn cannot_even_scorer_system(
     }
 }
 
+// Mynas tag along with the crows, matching their heading but keeping their distance.
+fn mixed_flock_affinities() -> SpeciesAffinities {
+    let mut affinities = SpeciesAffinities::default();
+    affinities.set(SPECIES_MYNA, SPECIES_CROW, Affinity {
+        separation: 2.5,
+        alignment: 1.0,
+        cohesion: 0.3,
+    });
+    affinities.set(SPECIES_CROW, SPECIES_MYNA, Affinity {
+        separation: 1.0,
+        alignment: 0.5,
+        cohesion: 0.2,
+    });
+    affinities
+}
+
+// Crows ride higher than the mynas, which keep nearer the treetops.
+fn species_altitude_bands() -> SpeciesAltitudeBands {
+    let mut bands = SpeciesAltitudeBands::default();
+    bands.0.insert(SPECIES_CROW, AltitudeBand {
+        min: 40.0,
+        max: 250.0,
+        softness: 30.0,
+    });
+    bands.0.insert(SPECIES_MYNA, AltitudeBand {
+        min: 20.0,
+        max: 90.0,
+        softness: 15.0,
+    });
+    bands
+}
+
 fn make_instance(
     commands: &mut Commands,
     asset_server: &Res<AssetServer>,
     model_filename: &str,
+    species: Species,
     position: Vec3,
     rotation: Quat,
 ) {
     let mut rng = rand::thread_rng();
 
+    let velocity = rotation * Vec3::Z * 50.;
+
     let gltf = asset_server.load(model_filename);
-    commands.spawn_bundle((
+    let mut crow = commands.spawn_bundle((
         ModelGLTF {
             handle: gltf,
         },
@@ -178,25 +277,121 @@ fn make_instance(
                 },
             ),
         Observable {
+            sees: LAYER_CROWS | LAYER_RAPTORS,
+            seen_on: LAYER_CROWS,
+            // Velocitator orients crows so their velocity points along +Z;
+            // the Flight model flies them along -Z.
+            view_forward: if CROWS_USE_FLIGHT_MODEL { -Vec3::Z } else { Vec3::Z },
+            blind_rear_half_angle: PI * 0.15,
             ..Default::default()
         },
         Velocitator {
-            velocity: rotation * Vec3::Z * 50.,
+            velocity,
             max_speed: rng.gen::<f32>() * 5.0 + 50.,
         },
         Separation {
             separation_factor: Vec3::ZERO,
-            weight: rng.gen::<f32>() * 0.1 + 0.1,
         },
         Alignment {
             alignment_factor: Vec3::ZERO,
-            weight: rng.gen::<f32>() * 0.1 + 0.1,
         },
         Cohesion {
             cohesion_factor: Vec3::ZERO,
-            weight: rng.gen::<f32>() * 0.01 + 0.01,
+        },
+        // A bit of individual variation on the default profile.
+        FlockingProfile {
+            separation_weight: rng.gen_range(0.3..0.5),
+            alignment_weight: rng.gen_range(0.3..0.5),
+            cohesion_weight: rng.gen_range(0.03..0.05),
+            ..default()
         },
     ));
+    crow
+        .insert(species)
+        .insert(ColliderAvoid::default())
+        .insert(KeepAltitude::default())
+        .insert(Prey::default())
+        // Let avoidance win out over flocking when it needs to.
+        .insert(SteeringMode::Prioritised { max_force: 150.0 });
+
+    if CROWS_USE_FLIGHT_MODEL {
+        let (ang_y, ang_x) = jaymath::vec3_to_yaw_pitch(velocity.normalize());
+        crow.insert_bundle((
+            Flyer {
+                speed_linear: velocity.length(),
+                ang_x,
+                ang_y,
+                ..default()
+            },
+            FlyerProps {
+                accel_max: 30.0,
+                spd_min: 25.0,
+                spd_max: 55.0,
+                ang_spd_x_max: 2.0,
+                ang_spd_y_max: 3.0,
+                ang_z_max: PI * 0.4,
+                ang_spd_z_max: 4.0,
+                // Scaled to these crows' speeds, stalling at around 20.
+                wing_loading: 30.0,
+                lift_coefficient: 1.2,
+                drag_coefficient: 0.1,
+                thrust_max: 15.0,
+            },
+            FlyerGoalVelocity {
+                velocity,
+            },
+            FlyerGoalComponents::default(),
+            SteersFlyer,
+        ));
+    }
+}
+
+// A shikra, to keep the crows on their toes.
+fn make_raptor(
+    commands: &mut Commands,
+    asset_server: &Res<AssetServer>,
+    position: Vec3,
+) {
+    commands.spawn_bundle((
+        ModelGLTF {
+            handle: asset_server.load("house_crow.glb"),
+        },
+        ModelWaitingToSpawn {},
+        Transform {
+            translation: position,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE * 0.15,
+        },
+        GlobalTransform::default(),
+        Name::new("Shikra"),
+        Observable {
+            sees: LAYER_CROWS,
+            seen_on: LAYER_RAPTORS,
+            view_forward: Vec3::Z,
+            view_range: 80.0,
+            view_half_angle: PI * 0.6,
+            ..Default::default()
+        },
+        Velocitator {
+            velocity: Vec3::Z * 60.,
+            max_speed: 60.,
+        },
+        Predator {
+            capture_distance: 4.0,
+            ..default()
+        },
+        ColliderAvoid::default(),
+        SteeringMode::Prioritised { max_force: 200.0 },
+    ));
+}
+
+fn crow_captured_system(
+    mut commands: Commands,
+    mut captures: EventReader<CaptureEvent>,
+) {
+    for capture in captures.iter() {
+        commands.entity(capture.prey).despawn_recursive();
+    }
 }
 
 
@@ -235,7 +430,38 @@ fn startup(
         material: materials.add(Color::rgb(0.4, 0.7, 0.3).into()),
         transform: Transform::from_translation(mid_bottom),
         ..default()
-    });
+    })
+        .insert(RigidBody::Static)
+        .insert(CollisionShape::Cuboid {
+            half_extends: Vec3::new(0.5 * bounds.x_size, 0.1, 0.5 * bounds.z_size),
+            border_radius: None,
+        })
+        // The HeightField keeps the crows off it already.
+        .insert(ProbesIgnore);
+
+    // Trees, for the crows to weave between.
+    let mut rng = rand::thread_rng();
+    let trunk = meshes.add(Mesh::from(shape::Capsule {
+        radius: 4.0,
+        depth: 60.0,
+        ..default()
+    }));
+    let bark = materials.add(Color::rgb(0.35, 0.25, 0.15).into());
+    for _ in 0..60 {
+        let x = rng.gen::<f32>() * bounds.x_size + bounds.x_min;
+        let z = rng.gen::<f32>() * bounds.z_size + bounds.z_min;
+        commands.spawn_bundle(PbrBundle {
+            mesh: trunk.clone(),
+            material: bark.clone(),
+            transform: Transform::from_xyz(x, 34.0, z),
+            ..default()
+        })
+            .insert(RigidBody::Static)
+            .insert(CollisionShape::Capsule {
+                half_segment: 30.0,
+                radius: 4.0,
+            });
+    }
 
     // Light
     commands.spawn_bundle(DirectionalLightBundle {
@@ -254,10 +480,10 @@ fn startup(
     });
 
 
-    let count = 300;
-    let mut rng = rand::thread_rng();
+    let crow_count = 300;
+    let myna_count = 80;
 
-    for _ in 0..count
+    for i in 0..crow_count + myna_count
     {
         let x = rng.gen::<f32>() * bounds.x_size as f32 + bounds.x_min;
         let y = rng.gen::<f32>() * bounds.y_size as f32 + bounds.y_min;
@@ -265,12 +491,17 @@ fn startup(
 
         let rot = -PI * 0.25 + rng.gen::<f32>() * PI * 0.5;
 
+        let species = if i < crow_count { SPECIES_CROW } else { SPECIES_MYNA };
+
         make_instance(
             &mut commands,
             &asset_server,
             "house_crow.glb",
+            species,
             Vec3::from((x, y, z)),
             Quat::from_rotation_y(rot),
         );
     }
+
+    make_raptor(&mut commands, &asset_server, mid_point);
 }
\ No newline at end of file
@@ -5,11 +5,14 @@ use crate::bounds;
 use bounds::*;
 use crate::flight;
 use flight::*;
+use crate::altitude;
+use altitude::*;
 
 use bevy::{
     prelude::*,
 };
 use bevy_editor_pls::prelude::*;
+use bevy_inspector_egui::InspectorPlugin;
 use rand::prelude::*;
 
 use smooth_bevy_cameras::{
@@ -36,6 +39,15 @@ pub fn start_bevy() {
         .add_plugin(EditorPlugin) // bevy_editor_pls, press E!
         .add_plugin(JayAnimation)
         .add_plugin(Flight)
+        // Or FlightModel::Aerodynamic, to fly on lift and drag; either can be picked in the inspector.
+        .insert_resource(FlightModel::Kinematic)
+        .add_plugin(InspectorPlugin::<FlightModel>::new())
+        .add_plugin(JayAltitude)
+        .insert_resource(AltitudeBand {
+            min: 5.0,
+            max: 40.0,
+            softness: 5.0,
+        })
         .insert_resource(AmbientLight {
             color: Color::WHITE,
             brightness: 1.0,
@@ -108,20 +120,31 @@ fn make_instance(
             scale: Vec3::ONE,
         },
         Name::new(format!("Flyer '{}'", model_filename)),
+        // Already at cruising speed, so flying aerodynamically doesn't start out stalled.
         Flyer {
-            speed_linear: 0.0,
+            speed_linear: 5.0,
             accel_linear: 0.0,
             ang_x: 0.0,
             ang_y: 0.0,
             ang_x_vel: 0.0,
             ang_y_vel: 0.0,
+            ang_z: 0.0,
+            ang_z_vel: 0.0,
+            speed_vertical: 0.0,
         },
         FlyerProps {
             accel_max: 3.0,
-            spd_min: 1.0,
+            // Stalls at about 2.6 with these wings.
+            spd_min: 3.5,
             spd_max: 7.0,
             ang_spd_x_max: 2.0,
             ang_spd_y_max: 2.0,
+            ang_z_max: PI * 0.35,
+            ang_spd_z_max: 3.0,
+            wing_loading: 0.5,
+            lift_coefficient: 1.2,
+            drag_coefficient: 0.1,
+            thrust_max: 8.0,
         },
         FlyerGoalVelocity
         {
@@ -139,6 +162,7 @@ fn make_instance(
             timer_min: 1.0,
             timer_max: 10.0,
         },
+        KeepAltitude::default(),
     ));
 }
 
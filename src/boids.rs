@@ -1,10 +1,16 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use bevy::{
     prelude::*,
+    tasks::ComputeTaskPool,
 };
+use bevy_inspector_egui::Inspectable;
 use crate::observe;
 use observe::*;
 use crate::velocitate;
 use velocitate::*;
+use crate::occlusion;
+use occlusion::*;
 
 
 // Our own plugin:
@@ -13,112 +19,562 @@ pub struct JayBoids;
 impl Plugin for JayBoids {
     fn build(&self, app: &mut App) {
         app
-            .add_system(separation_system)
-            .add_system(alignment_system)
-            .add_system(cohesion_system);
+            .init_resource::<FlockingProfile>()
+            .init_resource::<SpeciesFlockingProfiles>()
+            .init_resource::<SpeciesAffinities>()
+            .add_system(flockmates_insert_system)
+            .add_system(flockmates_system.label(BoidsSystem::Flockmates).after(ObserveSystem::Occlusion))
+            .add_system(separation_system.label(BoidsSystem::Rules).after(BoidsSystem::Flockmates).after(ObserveSystem::UpdateObserved).after(ObserveSystem::Occlusion))
+            .add_system(alignment_system.label(BoidsSystem::Rules).after(BoidsSystem::Flockmates).after(ObserveSystem::UpdateObserved).after(ObserveSystem::Occlusion))
+            .add_system(cohesion_system.label(BoidsSystem::Rules).after(BoidsSystem::Flockmates).after(ObserveSystem::UpdateObserved).after(ObserveSystem::Occlusion))
+            .register_type::<FlockingProfile>()
+            .register_type::<Species>();
     }
 }
 
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BoidsSystem {
+    Flockmates,
+    Rules,
+}
+
+// Which kind of animal something is, for picking its flocking profile.
+#[derive(Reflect, Component, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Component)]
+pub struct Species(pub usize);
+
+// How hard, and over what distances, the boids rules push.
+// The resource is the global default; the same thing as a component
+// overrides it for one entity. Species defaults sit in between.
+#[derive(Reflect, Inspectable, Component, Clone, Debug)]
+#[reflect(Component)]
+pub struct FlockingProfile {
+    pub separation_weight: f32,
+    pub alignment_weight: f32,
+    pub cohesion_weight: f32,
+    pub separation_radius: f32,
+    pub alignment_radius: f32,
+    pub cohesion_radius: f32,
+    // How quickly the separation push fades out towards separation_radius.
+    // Zero pushes the same at any distance inside it.
+    pub separation_falloff: f32,
+    // How many nearest flockmates count when going by topology rather than distance.
+    pub topological_k: usize,
+    // 0 goes by the radii above, 1 by the nearest topological_k, in between blends the two.
+    // Separation still ignores anything outside separation_radius either way.
+    pub topological_blend: f32,
+}
+
+impl Default for FlockingProfile {
+    fn default() -> Self {
+        FlockingProfile {
+            separation_weight: 0.4,
+            alignment_weight: 0.4,
+            cohesion_weight: 0.04,
+            separation_radius: 15.0,
+            alignment_radius: 20.0,
+            cohesion_radius: 20.0,
+            separation_falloff: 0.0,
+            topological_k: 7,
+            topological_blend: 0.0,
+        }
+    }
+}
+
+// Flocking profiles by species, used for anything without its own.
+#[derive(Default)]
+pub struct SpeciesFlockingProfiles(pub HashMap<Species, FlockingProfile>);
+
+/// The profile that applies to something: its own, else its species', else the global one.
+pub fn resolve_profile<'a>(
+    global: &'a FlockingProfile,
+    per_species: &'a SpeciesFlockingProfiles,
+    own: Option<&'a FlockingProfile>,
+    species: Option<&Species>,
+) -> &'a FlockingProfile
+{
+    if let Some(own) = own { return own; }
+
+    species
+        .and_then(|species| per_species.0.get(species))
+        .unwrap_or(global)
+}
+
+/// As resolve_profile, for systems that work without JayBoids' profile
+/// resources too, going by the default profile when they're missing.
+pub fn resolve_profile_or_default<'a>(
+    global: Option<&'a FlockingProfile>,
+    per_species: Option<&'a SpeciesFlockingProfiles>,
+    own: Option<&'a FlockingProfile>,
+    species: Option<&Species>,
+) -> Cow<'a, FlockingProfile>
+{
+    if let Some(own) = own { return Cow::Borrowed(own); }
+
+    species
+        .zip(per_species)
+        .and_then(|(species, per_species)| per_species.0.get(species))
+        .or(global)
+        .map_or_else(|| Cow::Owned(FlockingProfile::default()), Cow::Borrowed)
+}
+
+/// Each rule's push (separation, alignment, cohesion), weighted as the profile
+/// says. Rules we don't have don't push.
+pub fn weighted_rules(
+    profile: &FlockingProfile,
+    separation: Option<&Separation>,
+    alignment: Option<&Alignment>,
+    cohesion: Option<&Cohesion>,
+) -> (Vec3, Vec3, Vec3)
+{
+    (
+        separation.map_or(Vec3::ZERO, |rule| rule.separation_factor * profile.separation_weight),
+        alignment.map_or(Vec3::ZERO, |rule| rule.alignment_factor * profile.alignment_weight),
+        cohesion.map_or(Vec3::ZERO, |rule| rule.cohesion_factor * profile.cohesion_weight),
+    )
+}
+
+// We only flock with things seen on the same layers as us, so that seeing
+// a predator doesn't mean following it.
+fn flocks_with(observable: &Observable, neighbour: &Neighbour) -> bool
+{
+    observable.seen_on & neighbour.seen_on != 0
+}
+
+// The nearest few flockmates we can see, however far off they are, for going
+// by topology rather than distance. Kept up to date for anything with a boids
+// rule whose profile blends in topology. They're picked from the whole index,
+// not just what's within view_range. Anything the occlusion pass has found
+// hidden is left out, but it only looks within view_range, so further off
+// than that nothing's hidden.
+#[derive(Component, Debug, Default)]
+pub struct Flockmates {
+    pub nearest: Vec<Neighbour>,
+}
+
+/// The `k` nearest flockmates inside our view cone (facing `forward`) and not
+/// `hidden`, nearest first. `k_nearest(n)` finds the n nearest on our layers.
+/// Looks no further than the nearest `4 * k`, so may find fewer than `k`.
+pub fn nearest_flockmates(
+    observable: &Observable,
+    forward: Vec3,
+    k: usize,
+    k_nearest: impl Fn(usize) -> Vec<Neighbour>,
+    hidden: impl Fn(&Neighbour) -> bool,
+) -> Vec<Neighbour>
+{
+    if k == 0 { return Vec::new(); }
+
+    // Some of the nearest may be out of view, so ask for more until enough
+    // are in it, there are no more to be had, or we've asked for plenty.
+    let most = 4 * k;
+    let mut asked = k;
+    loop {
+        let found = k_nearest(asked);
+        let all_of_them = found.len() < asked;
+
+        let mut seen: Vec<Neighbour> = found.into_iter()
+            .filter(|neighbour| flocks_with(observable, neighbour)
+                && observable.can_see(forward, neighbour.offset)
+                && !hidden(neighbour))
+            .collect();
+        if seen.len() >= k || all_of_them || asked >= most {
+            seen.truncate(k);
+            return seen;
+        }
+        asked = (asked * 2).min(most);
+    }
+}
+
+// Anything with a boids rule gets somewhere to keep its nearest flockmates.
+fn flockmates_insert_system(
+    mut commands: Commands,
+    query: Query<Entity, (Without<Flockmates>, Or<(With<Separation>, With<Alignment>, With<Cohesion>)>)>,
+)
+{
+    for entity in query.iter() {
+        commands.entity(entity).insert(Flockmates::default());
+    }
+}
+
+// How many entities each task finds flockmates for.
+const FLOCKMATES_BATCH_SIZE: usize = 32;
+
+fn flockmates_system(
+    pool: Res<ComputeTaskPool>,
+    global: Res<FlockingProfile>,
+    per_species: Res<SpeciesFlockingProfiles>,
+    stuff: Res<StuffsToObserve>,
+    occlusion: Option<Res<OcclusionCache>>,
+    mut query: Query<(&mut Flockmates, &Observable, &Transform, Entity, Option<&FlockingProfile>, Option<&Species>)>,
+)
+{
+    let global: &FlockingProfile = &global;
+    let per_species: &SpeciesFlockingProfiles = &per_species;
+    let stuff: &StuffsToObserve = &stuff;
+    let occlusion: Option<&OcclusionCache> = occlusion.as_deref();
+
+    query.par_for_each_mut(&pool, FLOCKMATES_BATCH_SIZE, |(mut flockmates, observable, transform, entity, own, species)| {
+        let profile = resolve_profile(global, per_species, own, species);
+        if profile.topological_blend <= 0. {
+            flockmates.nearest.clear();
+            return;
+        }
+
+        let layers = observable.sees & observable.seen_on;
+        let forward = transform.rotation * observable.view_forward;
+        flockmates.nearest = nearest_flockmates(
+            observable,
+            forward,
+            profile.topological_k,
+            |n| stuff.k_nearest(transform.translation, n, layers, Some(entity)),
+            |neighbour| occlusion.map_or(false, |cache| cache.is_blocked(entity, neighbour.entity)),
+        );
+    });
+}
+
+fn nearest_of(flockmates: Option<&Flockmates>) -> &[Neighbour]
+{
+    flockmates.map_or(&[], |flockmates| &flockmates.nearest)
+}
+
+// Which flockmates a rule looks at: everything in view within a distance, or
+// the nearest few found for us in Flockmates.
+#[derive(Clone, Copy, Debug)]
+enum Neighbourhood<'a> {
+    Metric(f32),
+    Topological(&'a [Neighbour]),
+}
+
+// Our flockmates in a neighbourhood, nearest first.
+fn neighbourhood<'a>(observable: &'a Observable, hood: Neighbourhood<'a>) -> impl Iterator<Item = &'a Neighbour>
+{
+    let (radius, nearest): (Option<f32>, &[Neighbour]) = match hood {
+        Neighbourhood::Metric(radius) => (Some(radius), &[]),
+        Neighbourhood::Topological(nearest) => (None, nearest),
+    };
+
+    observable.observed.iter()
+        .filter(move |neighbour| flocks_with(observable, neighbour))
+        .take_while(move |neighbour| radius.map_or(false, |radius| neighbour.distance <= radius))
+        .chain(nearest.iter())
+}
+
+// Apply a rule over the metric neighbourhood, the topological one, or a blend
+// of the two, as the profile says.
+fn blend_neighbourhoods<'a>(profile: &FlockingProfile, radius: f32, nearest: &'a [Neighbour], rule: impl Fn(Neighbourhood<'a>) -> Vec3) -> Vec3
+{
+    let blend = profile.topological_blend.clamp(0.0, 1.0);
+    let metric = Neighbourhood::Metric(radius);
+    let topological = Neighbourhood::Topological(nearest);
+
+    if blend <= 0. { return rule(metric); }
+    if blend >= 1. { return rule(topological); }
+    rule(metric) * (1.0 - blend) + rule(topological) * blend
+}
+
+// How much one species minds another, as multipliers on each boids rule.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Affinity {
+    pub separation: f32,
+    pub alignment: f32,
+    pub cohesion: f32,
+}
+
+impl Default for Affinity {
+    fn default() -> Self {
+        Affinity {
+            separation: 1.0,
+            alignment: 1.0,
+            cohesion: 1.0,
+        }
+    }
+}
+
+// Affinities between species pairs, as (us, them), for mixed-species flocks.
+// Pairs not listed get `default`. Anything without a Species counts as Species(0).
+#[derive(Default)]
+pub struct SpeciesAffinities {
+    pub default: Affinity,
+    pub pairs: HashMap<(Species, Species), Affinity>,
+}
+
+impl SpeciesAffinities {
+    /// How `us` responds to `them`.
+    pub fn get(&self, us: Species, them: Species) -> Affinity
+    {
+        self.pairs.get(&(us, them)).copied().unwrap_or(self.default)
+    }
+
+    pub fn set(&mut self, us: Species, them: Species, affinity: Affinity)
+    {
+        self.pairs.insert((us, them), affinity);
+    }
+
+    /// Set how each of the two responds to the other alike.
+    pub fn set_mutual(&mut self, a: Species, b: Species, affinity: Affinity)
+    {
+        self.set(a, b, affinity);
+        self.set(b, a, affinity);
+    }
+}
+
+// The affinity between us and a neighbour.
+fn affinity_with(affinities: &SpeciesAffinities, species: &Query<&Species>, us: Option<&Species>, neighbour: &Neighbour) -> Affinity
+{
+    let them = species.get(neighbour.entity).copied().unwrap_or_default();
+    affinities.get(us.copied().unwrap_or_default(), them)
+}
+
 #[derive(Component, Debug)]
 pub struct Separation {
     pub separation_factor: Vec3,
-    pub weight :f32,
 }
 
 fn separation_system(
-    mut query_us: Query<(&Transform, &mut Separation, &Observable, Entity)>,
-    query_others: Query<&Transform>,
+    global: Res<FlockingProfile>,
+    per_species: Res<SpeciesFlockingProfiles>,
+    affinities: Res<SpeciesAffinities>,
+    all_species: Query<&Species>,
+    mut query_us: Query<(&mut Separation, &Observable, Option<&Flockmates>, Option<&FlockingProfile>, Option<&Species>)>,
 ) {
-    for (transform, mut separation, observable, entity) in query_us.iter_mut() {
-        let mut away = Vec3::ZERO;
-        let observed = &observable.observed;
-        for ent_nearby in observed.into_iter()
-        {
-            if *ent_nearby == entity { continue; }
-
-            if let Ok(other_transform) = query_others.get(*ent_nearby)
-            {
-                let displacement = other_transform.translation - transform.translation;
+    for (mut separation, observable, flockmates, own, species) in query_us.iter_mut() {
+        let profile = resolve_profile(&global, &per_species, own, species);
 
-                if displacement.length() < 15.
-                {
-                    away -= displacement;
-                }
-            }
-        }
+        separation.separation_factor = blend_neighbourhoods(profile, profile.separation_radius, nearest_of(flockmates), |hood| {
+            separate(observable, hood, profile, |neighbour| affinity_with(&affinities, &all_species, species, neighbour).separation)
+        });
+    }
+}
+
+// Push away from whoever in the neighbourhood is inside separation_radius,
+// going by distance or by topology, each push scaled by `affinity`.
+fn separate(observable: &Observable, hood: Neighbourhood, profile: &FlockingProfile, affinity: impl Fn(&Neighbour) -> f32) -> Vec3
+{
+    let radius = profile.separation_radius;
+    let mut away = Vec3::ZERO;
+    for neighbour in neighbourhood(observable, hood)
+    {
+        // Nearest first, so we can stop once we're past the separation radius.
+        if neighbour.distance >= radius { break; }
 
-        separation.separation_factor = away;
+        let strength = (1.0 - neighbour.distance / radius).powf(profile.separation_falloff) * affinity(neighbour);
+        away -= neighbour.offset * strength;
     }
+    away
 }
 
 #[derive(Component, Debug)]
 pub struct Alignment {
     pub alignment_factor: Vec3,
-    pub weight :f32,
 }
 
 fn alignment_system(
-    mut query_us: Query<(&mut Alignment, &Observable, &Velocitator, Entity)>,
-    query_others: Query<&Velocitator>,
+    global: Res<FlockingProfile>,
+    per_species: Res<SpeciesFlockingProfiles>,
+    affinities: Res<SpeciesAffinities>,
+    all_species: Query<&Species>,
+    mut query_us: Query<(&mut Alignment, &Observable, &Velocitator, Option<&Flockmates>, Option<&FlockingProfile>, Option<&Species>)>,
 )
 {
-    for (mut alignment, observable, velocitator, entity) in query_us.iter_mut() {
-        let mut align_vel = Vec3::ZERO;
+    for (mut alignment, observable, velocitator, flockmates, own, species) in query_us.iter_mut() {
+        let profile = resolve_profile(&global, &per_species, own, species);
 
-        let observed = &observable.observed;
-        let mut count = 0;
+        alignment.alignment_factor = blend_neighbourhoods(profile, profile.alignment_radius, nearest_of(flockmates), |hood| {
+            // Each neighbour's pull towards its velocity is scaled by our affinity for it.
+            // Anything that isn't going anywhere of its own accord has no heading to match.
+            let mut align = Vec3::ZERO;
+            let mut count = 0;
 
-        for ent_nearby in observed.into_iter()
-        {
-            if *ent_nearby == entity { continue; }
-
-            if let Ok(other_velocitator) = query_others.get(*ent_nearby)
+            for neighbour in neighbourhood(observable, hood)
             {
-                align_vel += other_velocitator.velocity;
+                let velocity = match neighbour.velocity {
+                    Some(velocity) => velocity,
+                    None => continue,
+                };
+                let weight = affinity_with(&affinities, &all_species, species, neighbour).alignment;
+                align += (velocity - velocitator.velocity) * weight;
                 count += 1;
             }
-        }
 
-        if count > 0
-        {
-            alignment.alignment_factor = align_vel / count as f32 - velocitator.velocity;
-        } else {
-            alignment.alignment_factor = Vec3::ZERO;
-            // println!("Nothing found for alignment in cell {}.", observable.cell);
-        }
+            if count > 0
+            {
+                align / count as f32
+            } else {
+                // println!("Nothing found for alignment in cell {}.", observable.cell);
+                Vec3::ZERO
+            }
+        });
     }
 }
 
 #[derive(Component, Debug)]
 pub struct Cohesion {
     pub cohesion_factor: Vec3,
-    pub weight :f32,
 }
 
 fn cohesion_system(
-    mut query_us: Query<(&Transform, &mut Cohesion, &Observable, Entity)>,
-    query_others: Query<&Transform>,
+    global: Res<FlockingProfile>,
+    per_species: Res<SpeciesFlockingProfiles>,
+    affinities: Res<SpeciesAffinities>,
+    all_species: Query<&Species>,
+    mut query_us: Query<(&mut Cohesion, &Observable, Option<&Flockmates>, Option<&FlockingProfile>, Option<&Species>)>,
 ) {
-    for (transform, mut cohesion, observable, entity) in query_us.iter_mut() {
-        let observed = &observable.observed;
-        let mut avg_pos = Vec3::ZERO;
-        let mut count = 0;
-
-        for ent_nearby in observed.into_iter()
-        {
-            if *ent_nearby == entity { continue; }
-            if let Ok(other_transform) = query_others.get(*ent_nearby)
+    for (mut cohesion, observable, flockmates, own, species) in query_us.iter_mut() {
+        let profile = resolve_profile(&global, &per_species, own, species);
+
+        cohesion.cohesion_factor = blend_neighbourhoods(profile, profile.cohesion_radius, nearest_of(flockmates), |hood| {
+            // The average offset to our neighbours points at their centre.
+            let mut avg_offset = Vec3::ZERO;
+            let mut count = 0;
+            for neighbour in neighbourhood(observable, hood)
             {
-                avg_pos += other_transform.translation;
+                let weight = affinity_with(&affinities, &all_species, species, neighbour).cohesion;
+                avg_offset += neighbour.offset * weight;
                 count += 1;
             }
+
+            if count > 0 {
+                avg_offset / count as f32
+            } else {
+                // println!("Nothing found for cohesion in cell {}.", observable.cell);
+                Vec3::ZERO
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn affinities_default_and_go_one_way() {
+        let (crow, myna) = (Species(0), Species(1));
+        let mut affinities = SpeciesAffinities::default();
+        let wary = Affinity { separation: 2.0, alignment: 1.0, cohesion: 0.2 };
+        affinities.set(myna, crow, wary);
+
+        assert_eq!(affinities.get(myna, crow), wary);
+        assert_eq!(affinities.get(crow, myna), Affinity::default());
+        assert_eq!(affinities.get(crow, crow), Affinity::default());
+
+        affinities.set_mutual(crow, myna, wary);
+        assert_eq!(affinities.get(crow, myna), wary);
+    }
+
+    fn neighbour_at(i: u32, distance: f32, seen_on: u32) -> Neighbour {
+        Neighbour {
+            entity: Entity::from_raw(i),
+            offset: Vec3::X * distance,
+            distance,
+            velocity: None,
+            seen_on,
         }
-        if count > 0 {
-            cohesion.cohesion_factor = avg_pos / count as f32 - transform.translation;
-        } else {
-            // Reset factor.
-            cohesion.cohesion_factor = Vec3::ZERO;
-            // println!("Nothing found for cohesion in cell {}.", observable.cell);
-        }
+    }
+
+    #[test]
+    fn profiles_default_without_resources() {
+        let own = FlockingProfile { separation_weight: 2.0, ..Default::default() };
+        let crow = FlockingProfile { separation_weight: 3.0, ..Default::default() };
+        let mut per_species = SpeciesFlockingProfiles::default();
+        per_species.0.insert(Species(1), crow);
+
+        assert_eq!(resolve_profile_or_default(None, None, Some(&own), None).separation_weight, 2.0);
+        assert_eq!(resolve_profile_or_default(None, Some(&per_species), None, Some(&Species(1))).separation_weight, 3.0);
+        assert_eq!(resolve_profile_or_default(None, None, None, Some(&Species(1))).separation_weight, FlockingProfile::default().separation_weight);
+    }
+
+    #[test]
+    fn neighbourhoods_by_distance_or_count() {
+        let mut observable = Observable::default();
+        observable.observed = vec![
+            neighbour_at(0, 1.0, LAYER_DEFAULT),
+            neighbour_at(1, 2.0, 0b10),
+            neighbour_at(2, 3.0, LAYER_DEFAULT),
+            neighbour_at(3, 30.0, LAYER_DEFAULT),
+            neighbour_at(4, 40.0, LAYER_DEFAULT),
+        ];
+        let nearest = vec![neighbour_at(5, 60.0, LAYER_DEFAULT)];
+        let found = |hood| neighbourhood(&observable, hood).map(|n| n.entity).collect::<Vec<_>>();
+
+        assert_eq!(found(Neighbourhood::Metric(10.0)), vec![Entity::from_raw(0), Entity::from_raw(2)]);
+        assert_eq!(found(Neighbourhood::Topological(&nearest)), vec![Entity::from_raw(5)]);
+    }
+
+    #[test]
+    fn nearest_flockmates_reach_past_view_range_and_skip_unseen() {
+        let observable = Observable {
+            view_half_angle: PI * 0.5,
+            ..Default::default()
+        };
+        // Every other one is behind us, and one isn't a flockmate.
+        let everyone: Vec<Neighbour> = (0..20)
+            .map(|i| {
+                let mut neighbour = neighbour_at(i, 10.0 + i as f32 * 10.0, if i == 2 { 0b10 } else { LAYER_DEFAULT });
+                neighbour.offset = if i % 2 == 0 { -Vec3::Z } else { Vec3::Z } * neighbour.distance;
+                neighbour
+            })
+            .collect();
+        let k_nearest = |n: usize| everyone.iter().take(n).copied().collect::<Vec<_>>();
+
+        let nobody_hidden = |_: &Neighbour| false;
+
+        let found: Vec<Entity> = nearest_flockmates(&observable, -Vec3::Z, 3, k_nearest, nobody_hidden).iter().map(|n| n.entity).collect();
+        assert_eq!(found, vec![Entity::from_raw(0), Entity::from_raw(4), Entity::from_raw(6)]);
+
+        // Hidden ones are skipped too.
+        let found: Vec<Entity> = nearest_flockmates(&observable, -Vec3::Z, 3, k_nearest, |n| n.entity == Entity::from_raw(4))
+            .iter().map(|n| n.entity).collect();
+        assert_eq!(found, vec![Entity::from_raw(0), Entity::from_raw(6), Entity::from_raw(8)]);
+
+        // We don't look past the nearest 4 * k for them, though 6 is further on.
+        let from_1 = |n: usize| everyone.iter().skip(1).take(n).copied().collect::<Vec<_>>();
+        assert!(nearest_flockmates(&observable, -Vec3::Z, 1, from_1, |n| n.entity == Entity::from_raw(4)).is_empty());
+
+        // Sparse: fewer than k to be had, so we get what there is.
+        assert_eq!(nearest_flockmates(&observable, -Vec3::Z, 15, k_nearest, nobody_hidden).len(), 9);
+    }
+
+    #[test]
+    fn topological_separation_leaves_a_spaced_flock_be() {
+        let profile = FlockingProfile {
+            topological_blend: 1.0,
+            ..Default::default()
+        };
+        let observable = Observable::default();
+        let no_affinity = |_: &Neighbour| 1.0;
+
+        // A flock at rest, its nearest all further off than separation_radius.
+        let ring: Vec<Neighbour> = (0..profile.topological_k as u32)
+            .map(|i| {
+                let mut neighbour = neighbour_at(i, 20.0, LAYER_DEFAULT);
+                neighbour.offset = Quat::from_rotation_y(i as f32) * Vec3::X * 20.0;
+                neighbour
+            })
+            .collect();
+        assert_eq!(separate(&observable, Neighbourhood::Topological(&ring), &profile, no_affinity), Vec3::ZERO);
+
+        // Only whoever's too close pushes.
+        let mut crowded = ring.clone();
+        crowded.insert(0, neighbour_at(99, 5.0, LAYER_DEFAULT));
+        let away = separate(&observable, Neighbourhood::Topological(&crowded), &profile, no_affinity);
+        assert_eq!(away, -Vec3::X * 5.0);
+    }
+
+    #[test]
+    fn blend_mixes_metric_and_topological() {
+        let mut profile = FlockingProfile::default();
+        let rule = |hood| match hood {
+            Neighbourhood::Metric(_) => Vec3::X,
+            Neighbourhood::Topological(_) => Vec3::Y,
+        };
+
+        assert_eq!(blend_neighbourhoods(&profile, 20.0, &[], rule), Vec3::X);
+        profile.topological_blend = 0.25;
+        assert_eq!(blend_neighbourhoods(&profile, 20.0, &[], rule), Vec3::new(0.75, 0.25, 0.0));
+        profile.topological_blend = 1.0;
+        assert_eq!(blend_neighbourhoods(&profile, 20.0, &[], rule), Vec3::Y);
     }
 }
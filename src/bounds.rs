@@ -1,3 +1,7 @@
+use bevy::{
+    prelude::*,
+};
+
 pub struct Bounds {
     pub cell_size: f32,
     pub x_min: f32,
@@ -11,7 +15,12 @@ pub struct Bounds {
     pub z_size: f32,
     pub margin: f32,
     pub cells_x: usize,
+    pub cells_y: usize,
     pub cells_z: usize,
+    // Axes along which the world wraps around instead of having walls.
+    pub wrap_x: bool,
+    pub wrap_y: bool,
+    pub wrap_z: bool,
 }
 
 impl Bounds {
@@ -20,6 +29,7 @@ impl Bounds {
         let y_size = y_max - y_min;
         let z_size = z_max - z_min;
         let cells_x = (x_size / cell_size) as usize;
+        let cells_y = (y_size / cell_size) as usize;
         let cells_z = (z_size / cell_size) as usize;
         Bounds {
             cell_size,
@@ -34,7 +44,90 @@ impl Bounds {
             y_size,
             z_size,
             cells_x,
+            cells_y,
             cells_z,
+            wrap_x: false,
+            wrap_y: false,
+            wrap_z: false,
+        }
+    }
+
+    // Make the world toroidal along some axes.
+    pub fn wrapped(mut self, x: bool, y: bool, z: bool) -> Bounds {
+        self.wrap_x = x;
+        self.wrap_y = y;
+        self.wrap_z = z;
+        self
+    }
+
+    pub fn wrap(&self) -> Wrap {
+        Wrap {
+            min: Vec3::new(self.x_min, self.y_min, self.z_min),
+            size: Vec3::new(self.x_size, self.y_size, self.z_size),
+            axes: [self.wrap_x, self.wrap_y, self.wrap_z],
+        }
+    }
+}
+
+// Just the wrap-around part of Bounds, small enough to copy about.
+#[derive(Clone, Copy, Debug)]
+pub struct Wrap {
+    pub min: Vec3,
+    pub size: Vec3,
+    pub axes: [bool; 3],
+}
+
+impl Wrap {
+    pub fn any(&self) -> bool
+    {
+        self.axes.iter().any(|wrapped| *wrapped)
+    }
+
+    /// Bring a position back inside the box along the wrapped axes.
+    pub fn position(&self, pos: Vec3) -> Vec3
+    {
+        let mut wrapped = pos;
+        for axis in 0..3 {
+            if self.axes[axis] && self.size[axis] > 0.0 {
+                wrapped[axis] = self.min[axis] + (pos[axis] - self.min[axis]).rem_euclid(self.size[axis]);
+            }
+        }
+        wrapped
+    }
+
+    /// The shortest way from `from` to `to`, which may be across a seam.
+    pub fn offset(&self, from: Vec3, to: Vec3) -> Vec3
+    {
+        let mut offset = to - from;
+        for axis in 0..3 {
+            if self.axes[axis] && self.size[axis] > 0.0 {
+                offset[axis] -= self.size[axis] * (offset[axis] / self.size[axis]).round();
+            }
         }
+        offset
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap_xz() -> Wrap {
+        Bounds::new(10., 0., 100., 0., 50., -50., 50., 10.).wrapped(true, false, true).wrap()
+    }
+
+    #[test]
+    fn position_wraps_only_wrapped_axes() {
+        let wrap = wrap_xz();
+        assert_eq!(wrap.position(Vec3::new(105.0, 60.0, -55.0)), Vec3::new(5.0, 60.0, 45.0));
+        assert_eq!(wrap.position(Vec3::new(-1.0, -1.0, 0.0)), Vec3::new(99.0, -1.0, 0.0));
+    }
+
+    #[test]
+    fn offset_takes_the_short_way() {
+        let wrap = wrap_xz();
+        assert_eq!(wrap.offset(Vec3::new(95.0, 0.0, 0.0), Vec3::new(5.0, 0.0, 0.0)), Vec3::new(10.0, 0.0, 0.0));
+        assert_eq!(wrap.offset(Vec3::new(0.0, 0.0, -45.0), Vec3::new(0.0, 0.0, 45.0)), Vec3::new(0.0, 0.0, -10.0));
+        assert_eq!(wrap.offset(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, 45.0, 0.0)), Vec3::new(0.0, 40.0, 0.0));
+    }
+}
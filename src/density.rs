@@ -0,0 +1,259 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use bevy::{
+    app::AppExit,
+    prelude::*,
+};
+
+use crate::observe;
+use observe::*;
+use crate::bounds;
+use bounds::*;
+
+// Our own plugin. Keeps a running tally of how many observables are in each
+// Bounds cell, and writes it out as CSV for comparing against survey grids.
+pub struct JayDensity;
+
+impl Plugin for JayDensity {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<DensityExport>()
+            .add_system_to_stage(CoreStage::PostUpdate, density_accumulate_system.after(ObserveSystem::UpdateIndex))
+            // Last, so an AppExit sent any time this frame is seen before the app stops.
+            .add_system_to_stage(CoreStage::Last, density_export_system);
+    }
+}
+
+// Where and how often to write the density out.
+pub struct DensityExport {
+    pub path: Option<PathBuf>,
+    // Sum over altitude, giving one row per x/z column like a ground survey.
+    pub collapse_altitude: bool,
+    // Also write every so many seconds, not just on exit.
+    pub every_seconds: Option<f32>,
+    pub timer: f32,
+}
+
+impl Default for DensityExport {
+    fn default() -> Self {
+        DensityExport {
+            path: None,
+            collapse_altitude: true,
+            every_seconds: Some(10.0),
+            timer: 0.0,
+        }
+    }
+}
+
+// Occupancy of each Bounds cell over time. Cells are laid out x first, then z, then y.
+pub struct CellDensity {
+    origin: Vec3,
+    cell_size: f32,
+    cells_x: usize,
+    cells_y: usize,
+    cells_z: usize,
+    // Count times seconds, summed.
+    occupancy: Vec<f32>,
+    peak: Vec<u32>,
+    current: Vec<u32>,
+    elapsed: f32,
+}
+
+impl CellDensity {
+    pub fn new(bounds: &Bounds) -> CellDensity {
+        let cells_x = bounds.cells_x.max(1);
+        let cells_y = bounds.cells_y.max(1);
+        let cells_z = bounds.cells_z.max(1);
+        let size = cells_x * cells_y * cells_z;
+        CellDensity {
+            origin: Vec3::new(bounds.x_min, bounds.y_min, bounds.z_min),
+            cell_size: bounds.cell_size,
+            cells_x,
+            cells_y,
+            cells_z,
+            occupancy: vec![0.0; size],
+            peak: vec![0; size],
+            current: vec![0; size],
+            elapsed: 0.0,
+        }
+    }
+
+    fn cell(&self, pos: Vec3) -> Option<usize>
+    {
+        if self.cell_size <= 0. { return None; }
+
+        let c = ((pos - self.origin) / self.cell_size).floor();
+        if c.x < 0. || c.y < 0. || c.z < 0. { return None; }
+
+        let (x, y, z) = (c.x as usize, c.y as usize, c.z as usize);
+        if x >= self.cells_x || y >= self.cells_y || z >= self.cells_z { return None; }
+
+        Some(x + z * self.cells_x + y * self.cells_x * self.cells_z)
+    }
+
+    /// Count one frame's worth of positions, lasting `delta_time` seconds.
+    pub fn accumulate(&mut self, positions: impl Iterator<Item = Vec3>, delta_time: f32)
+    {
+        for count in self.current.iter_mut() {
+            *count = 0;
+        }
+        for pos in positions {
+            if let Some(cell) = self.cell(pos) {
+                self.current[cell] += 1;
+            }
+        }
+        for ((occupancy, peak), count) in self.occupancy.iter_mut().zip(self.peak.iter_mut()).zip(self.current.iter()) {
+            *occupancy += *count as f32 * delta_time;
+            *peak = (*peak).max(*count);
+        }
+        self.elapsed += delta_time;
+    }
+
+    /// Time-averaged count in a cell.
+    pub fn mean(&self, x: usize, y: usize, z: usize) -> f32
+    {
+        if self.elapsed <= 0. { return 0.; }
+        self.occupancy[x + z * self.cells_x + y * self.cells_x * self.cells_z] / self.elapsed
+    }
+
+    pub fn peak(&self, x: usize, y: usize, z: usize) -> u32
+    {
+        self.peak[x + z * self.cells_x + y * self.cells_x * self.cells_z]
+    }
+
+    pub fn reset(&mut self)
+    {
+        for occupancy in self.occupancy.iter_mut() { *occupancy = 0.0; }
+        for peak in self.peak.iter_mut() { *peak = 0; }
+        self.elapsed = 0.0;
+    }
+
+    /// One row per cell, with the cell's world-space minimum corner. When
+    /// collapsing altitude, means are summed over each column and the peak is
+    /// the highest of any cell in it.
+    pub fn to_csv(&self, collapse_altitude: bool) -> String
+    {
+        let mut csv = String::new();
+        if collapse_altitude {
+            csv.push_str("cell_x,cell_z,world_x,world_z,mean,peak\n");
+            for z in 0..self.cells_z {
+                for x in 0..self.cells_x {
+                    let mean: f32 = (0..self.cells_y).map(|y| self.mean(x, y, z)).sum();
+                    let peak = (0..self.cells_y).map(|y| self.peak(x, y, z)).max().unwrap_or(0);
+                    csv.push_str(&format!("{},{},{},{},{},{}\n",
+                        x, z,
+                        self.origin.x + x as f32 * self.cell_size,
+                        self.origin.z + z as f32 * self.cell_size,
+                        mean, peak));
+                }
+            }
+        } else {
+            csv.push_str("cell_x,cell_y,cell_z,world_x,world_y,world_z,mean,peak\n");
+            for y in 0..self.cells_y {
+                for z in 0..self.cells_z {
+                    for x in 0..self.cells_x {
+                        csv.push_str(&format!("{},{},{},{},{},{},{},{}\n",
+                            x, y, z,
+                            self.origin.x + x as f32 * self.cell_size,
+                            self.origin.y + y as f32 * self.cell_size,
+                            self.origin.z + z as f32 * self.cell_size,
+                            self.mean(x, y, z), self.peak(x, y, z)));
+                    }
+                }
+            }
+        }
+        csv
+    }
+
+    pub fn write_csv(&self, path: &PathBuf, collapse_altitude: bool) -> io::Result<()>
+    {
+        fs::write(path, self.to_csv(collapse_altitude))
+    }
+}
+
+fn density_accumulate_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    bounds: Res<Bounds>,
+    density: Option<ResMut<CellDensity>>,
+    observables: Query<&Transform, With<Observable>>,
+)
+{
+    let positions = observables.iter().map(|transform| transform.translation);
+    match density {
+        Some(mut density) => density.accumulate(positions, time.delta_seconds()),
+        None => {
+            let mut density = CellDensity::new(&bounds);
+            density.accumulate(positions, time.delta_seconds());
+            commands.insert_resource(density);
+        }
+    }
+}
+
+fn density_export_system(
+    time: Res<Time>,
+    mut export: ResMut<DensityExport>,
+    density: Option<Res<CellDensity>>,
+    mut exits: EventReader<AppExit>,
+)
+{
+    let exiting = exits.iter().next().is_some();
+
+    let mut due = false;
+    if let Some(every) = export.every_seconds {
+        export.timer += time.delta_seconds();
+        if export.timer >= every {
+            export.timer = 0.0;
+            due = true;
+        }
+    }
+
+    if !(due || exiting) { return; }
+
+    if let (Some(path), Some(density)) = (&export.path, density) {
+        if let Err(e) = density.write_csv(path, export.collapse_altitude) {
+            error!("Couldn't write density to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn density_2x2() -> CellDensity {
+        CellDensity::new(&Bounds::new(10., 0., 20., 0., 20., 0., 20., 0.))
+    }
+
+    #[test]
+    fn mean_and_peak_over_time() {
+        let mut density = density_2x2();
+        density.accumulate(vec![Vec3::splat(5.0), Vec3::splat(6.0)].into_iter(), 1.0);
+        density.accumulate(vec![Vec3::splat(5.0)].into_iter(), 3.0);
+
+        assert_eq!(density.peak(0, 0, 0), 2);
+        assert!((density.mean(0, 0, 0) - 1.25).abs() < 1e-6);
+        assert_eq!(density.mean(1, 1, 1), 0.0);
+    }
+
+    #[test]
+    fn outside_bounds_is_not_counted() {
+        let mut density = density_2x2();
+        density.accumulate(vec![Vec3::splat(-5.0), Vec3::splat(25.0)].into_iter(), 1.0);
+        assert!(density.peak.iter().all(|peak| *peak == 0));
+    }
+
+    #[test]
+    fn csv_collapses_altitude() {
+        let mut density = density_2x2();
+        density.accumulate(vec![Vec3::new(5.0, 5.0, 15.0), Vec3::new(5.0, 15.0, 15.0)].into_iter(), 1.0);
+
+        let csv = density.to_csv(true);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[3], "0,1,0,10,2,1");
+
+        assert_eq!(density.to_csv(false).lines().count(), 9);
+    }
+}
@@ -7,6 +7,8 @@ use bevy_inspector_egui::Inspectable;
 
 use crate::jaymath;
 use jaymath::*;
+use crate::wind;
+use wind::*;
 
 
 // Our own plugin:
@@ -15,9 +17,11 @@ pub struct Flight;
 impl Plugin for Flight {
     fn build(&self, app: &mut App) {
         app
-            .add_system(flyer_goals_reduce_to_components_system)
-            .add_system(flyer_steering_system.after(flyer_goals_reduce_to_components_system))
-            .add_system(flyer_movement_system.after(flyer_steering_system))
+            .init_resource::<FlightModel>()
+            .add_system(flyer_goals_reduce_to_components_system.label(FlightSystem::Goals))
+            .add_system(flyer_steering_system.label(FlightSystem::Steering).after(FlightSystem::Goals))
+            .add_system(flyer_movement_system.label(FlightSystem::Movement).after(FlightSystem::Steering))
+            .register_type::<FlightModel>()
             .register_type::<Flyer>()
             .register_type::<FlyerProps>()
             .register_type::<FlyerGoalVelocity>()
@@ -25,6 +29,37 @@ impl Plugin for Flight {
     }
 }
 
+const GRAVITY: f32 = 9.81;
+const AIR_DENSITY: f32 = 1.225;
+// How quickly flapping tries to make up the difference to the goal speed, in seconds.
+const THRUST_RESPONSE: f32 = 0.5;
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FlightSystem {
+    Goals,
+    Steering,
+    Movement,
+}
+
+// How a Flyer gets from its goals to moving. The resource is the default;
+// the same thing as a component picks for one flyer. Add an
+// InspectorPlugin::<FlightModel> to switch between them while running.
+#[derive(Reflect, Inspectable, Component, Clone, Copy, Debug, PartialEq)]
+#[reflect_value(Component, PartialEq)]
+pub enum FlightModel {
+    // Speed and angles head straight for the goal, within limits.
+    Kinematic,
+    // Speed comes from flapping against drag and gravity, and lift holds us
+    // up, so climbing costs speed, diving gains it and flying too slowly sinks.
+    Aerodynamic,
+}
+
+impl Default for FlightModel {
+    fn default() -> Self {
+        FlightModel::Kinematic
+    }
+}
+
 // A thing that is moving with forward and angular (xy only) speeds.
 #[derive(Reflect, Component, Default)]
 #[reflect(Component)]
@@ -36,6 +71,11 @@ pub struct Flyer
     pub ang_y: f32,
     pub ang_x_vel: f32,
     pub ang_y_vel: f32,
+    // Roll, from banking into turns.
+    pub ang_z: f32,
+    pub ang_z_vel: f32,
+    // Sinking (negative) for want of lift, when flying aerodynamically.
+    pub speed_vertical: f32,
 }
 
 
@@ -49,9 +89,18 @@ pub struct FlyerProps
     pub spd_max: f32,
     pub ang_spd_x_max: f32,
     pub ang_spd_y_max: f32,
+    // How far and how fast we'll bank.
+    pub ang_z_max: f32,
+    pub ang_spd_z_max: f32,
+    // For aerodynamic flight: mass per wing area, how much lift and drag the
+    // wings make, and the most acceleration flapping can give.
+    pub wing_loading: f32,
+    pub lift_coefficient: f32,
+    pub drag_coefficient: f32,
+    pub thrust_max: f32,
 }
 
-// The goal velocity that a flyer would like to achieve.
+// The goal velocity that a flyer would like to achieve, over the ground.
 #[derive(Reflect, Component, Default)]
 #[reflect(Component)]
 pub struct FlyerGoalVelocity
@@ -69,71 +118,229 @@ pub struct FlyerGoalComponents
     pub ang_y: f32,
 }
 
+// Speeds and headings are through the air, so with wind about we head for
+// the ground velocity less the wind, crabbing into any crosswind.
 fn flyer_goals_reduce_to_components_system(
     mut commands: Commands,
     time: Res<Time>,
+    wind: Option<Res<Wind>>,
     mut query: Query<(&Flyer, &Transform, &FlyerProps, &FlyerGoalVelocity, &mut FlyerGoalComponents, Entity)>,
 ) {
     for (flyer, transform, props, goal_velocity, mut goal_f32, entity) in query.iter_mut() {
-        let goal_speed = goal_velocity.velocity.length();
-        let goal_direction = if goal_speed > 0.0 { goal_velocity.velocity / goal_speed } else { Vec3::ZERO };
+        let air_velocity = match &wind {
+            Some(wind) => goal_velocity.velocity - wind.at(transform.translation),
+            None => goal_velocity.velocity,
+        };
+        let goal_speed = air_velocity.length();
+        let goal_direction = if goal_speed > 0.0 { air_velocity / goal_speed } else { Vec3::ZERO };
         let vel_dot = goal_direction.dot(transform.forward()).clamp(0.0, 1.0);
-        println!("goal_dirc: {}, forward: {}, dot: {}", goal_direction, transform.forward(), goal_direction.dot(transform.forward()));
-        goal_f32.speed_linear = props.spd_min.lerp(goal_speed, vel_dot).max(props.spd_max);
+        goal_f32.speed_linear = props.spd_min.lerp(goal_speed, vel_dot).clamp(props.spd_min, props.spd_max);
 
         // yaw, pitch
         (goal_f32.ang_y, goal_f32.ang_x) = jaymath::vec3_to_yaw_pitch(goal_direction);
     }
 }
 
+/// One step of aerodynamic flight: new airspeed along our heading, its rate
+/// of change, and new vertical speed. We flap to reach `goal_speed` as far as
+/// `thrust_max` allows, and use only as much lift as holding us up takes;
+/// any spare goes to stopping a sink.
+pub fn aerodynamic_step(
+    speed: f32,
+    speed_vertical: f32,
+    pitch: f32,
+    bank: f32,
+    goal_speed: f32,
+    props: &FlyerProps,
+    delta_time: f32,
+) -> (f32, f32, f32)
+{
+    let pressure = if props.wing_loading > 0. {
+        0.5 * AIR_DENSITY * speed * speed / props.wing_loading
+    } else {
+        0.0
+    };
+
+    let drag = pressure * props.drag_coefficient;
+    let climb = GRAVITY * pitch.sin();
+    let thrust = ((goal_speed - speed) / THRUST_RESPONSE + drag + climb).clamp(0.0, props.thrust_max);
+    let accel = thrust - drag - climb;
+    let speed_new = (speed + accel * delta_time).max(0.0);
+
+    // Banking tips lift over, leaving less to hold us up.
+    let lift = pressure * props.lift_coefficient * bank.cos().max(0.0);
+    let weight = GRAVITY * pitch.cos();
+    let mut vertical = speed_vertical + (lift.min(weight) - weight) * delta_time;
+    if vertical < 0. && lift > weight {
+        vertical = (vertical + (lift - weight) * delta_time).min(0.0);
+    }
+
+    (speed_new, accel, vertical)
+}
+
+/// One step of a Flyer's speed and angles towards its goal.
+pub fn steer_flyer(flyer: &mut Flyer, props: &FlyerProps, goal: &FlyerGoalComponents, model: FlightModel, delta_time: f32)
+{
+    match model {
+        FlightModel::Kinematic => {
+            let (spd_new, accel_new) = jaymath::smooth_damp(
+                flyer.speed_linear,
+                goal.speed_linear,
+                flyer.accel_linear,
+                0.1,
+                props.accel_max,
+                delta_time,
+            );
+            flyer.speed_linear = spd_new;
+            flyer.accel_linear = accel_new;
+            flyer.speed_vertical = 0.0;
+        }
+        FlightModel::Aerodynamic => {
+            let (spd_new, accel_new, vertical_new) = aerodynamic_step(
+                flyer.speed_linear,
+                flyer.speed_vertical,
+                flyer.ang_x,
+                flyer.ang_z,
+                goal.speed_linear,
+                props,
+                delta_time,
+            );
+            flyer.speed_linear = spd_new;
+            flyer.accel_linear = accel_new;
+            flyer.speed_vertical = vertical_new;
+        }
+    }
+
+    let (ang_x_new, ang_x_vel_new) = jaymath::smooth_damp_angle(
+        flyer.ang_x,
+        goal.ang_x,
+        flyer.ang_x_vel,
+        0.1,
+        props.ang_spd_x_max,
+        delta_time,
+    );
+    flyer.ang_x = ang_x_new;
+    flyer.ang_x_vel = ang_x_vel_new;
+
+    let (ang_y_new, ang_y_vel_new) = jaymath::smooth_damp_angle(
+        flyer.ang_y,
+        goal.ang_y,
+        flyer.ang_y_vel,
+        0.1,
+        props.ang_spd_y_max,
+        delta_time,
+    );
+    flyer.ang_y = ang_y_new;
+    flyer.ang_y_vel = ang_y_vel_new;
+
+    // Bank into the turn as much as a real bird would, within limits.
+    let bank = jaymath::coordinated_bank_angle(flyer.speed_linear, flyer.ang_y_vel, GRAVITY)
+        .clamp(-props.ang_z_max, props.ang_z_max);
+    let (ang_z_new, ang_z_vel_new) = jaymath::smooth_damp_angle(
+        flyer.ang_z,
+        bank,
+        flyer.ang_z_vel,
+        0.2,
+        props.ang_spd_z_max,
+        delta_time,
+    );
+    flyer.ang_z = ang_z_new;
+    flyer.ang_z_vel = ang_z_vel_new;
+}
+
 fn flyer_steering_system(
     mut commands: Commands,
     time: Res<Time>,
-    mut query: Query<(&mut Flyer, &Transform, &FlyerProps, &FlyerGoalComponents, Entity)>,
+    default_model: Res<FlightModel>,
+    mut query: Query<(&mut Flyer, &Transform, &FlyerProps, &FlyerGoalComponents, Option<&FlightModel>, Entity)>,
 ) {
-    for (mut flyer, transform, props, goal, entity) in query.iter_mut() {
-        let (spd_new, accel_new) = jaymath::smooth_damp(
-            flyer.speed_linear,
-            goal.speed_linear,
-            flyer.accel_linear,
-            0.1,
-            props.accel_max,
-            time.delta_seconds(),
-        );
-        flyer.speed_linear = spd_new;
-        flyer.accel_linear = accel_new;
-
-        let (ang_x_new, ang_x_vel_new) = jaymath::smooth_damp_angle(
-            flyer.ang_x,
-            goal.ang_x,
-            flyer.ang_x_vel,
-            0.1,
-            props.ang_spd_x_max,
-            time.delta_seconds(),
-        );
-        flyer.ang_x = ang_x_new;
-        flyer.ang_x_vel = ang_x_vel_new;
-
-        let (ang_y_new, ang_y_vel_new) = jaymath::smooth_damp_angle(
-            flyer.ang_y,
-            goal.ang_y,
-            flyer.ang_y_vel,
-            0.1,
-            props.ang_spd_y_max,
-            time.delta_seconds(),
-        );
-        flyer.ang_y = ang_y_new;
-        flyer.ang_y_vel = ang_y_vel_new;
+    for (mut flyer, transform, props, goal, model, entity) in query.iter_mut() {
+        steer_flyer(&mut flyer, props, goal, model.copied().unwrap_or(*default_model), time.delta_seconds());
     }
 }
 
 fn flyer_movement_system(
     mut commands: Commands,
     time: Res<Time>,
+    wind: Option<Res<Wind>>,
     mut query: Query<(&Flyer, &mut Transform, &FlyerProps, &FlyerGoalComponents, Entity)>,
 ) {
     for (flyer, mut transform, props, goal, entity) in query.iter_mut() {
-        transform.rotation = Quat::from_euler(EulerRot::YXZ, flyer.ang_y, flyer.ang_x, 0.0);
-        transform.translation = transform.translation + transform.forward() * flyer.speed_linear * time.delta_seconds();
+        let drift = wind.as_ref().map_or(Vec3::ZERO, |wind| wind.at(transform.translation));
+        transform.rotation = Quat::from_euler(EulerRot::YXZ, flyer.ang_y, flyer.ang_x, flyer.ang_z);
+        let velocity = transform.forward() * flyer.speed_linear + Vec3::Y * flyer.speed_vertical;
+        transform.translation = transform.translation + (velocity + drift) * time.delta_seconds();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crow() -> FlyerProps {
+        FlyerProps {
+            wing_loading: 3.0,
+            lift_coefficient: 1.2,
+            drag_coefficient: 0.1,
+            thrust_max: 6.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn a_frame_with_no_time_leaves_nothing_broken() {
+        let props = FlyerProps {
+            accel_max: 30.0,
+            spd_min: 5.0,
+            spd_max: 20.0,
+            ang_spd_x_max: 2.0,
+            ang_spd_y_max: 3.0,
+            ang_z_max: 1.0,
+            ang_spd_z_max: 4.0,
+            ..crow()
+        };
+        let mut flyer = Flyer { speed_linear: 10.0, ..Default::default() };
+
+        // Bevy's first frame takes no time, with everything still level.
+        steer_flyer(&mut flyer, &props, &FlyerGoalComponents { speed_linear: 10.0, ..Default::default() }, FlightModel::Kinematic, 0.0);
+
+        // Then turning left banks us.
+        let turn = FlyerGoalComponents { speed_linear: 10.0, ang_x: 0.0, ang_y: 1.0 };
+        for _ in 0..10 {
+            steer_flyer(&mut flyer, &props, &turn, FlightModel::Kinematic, 1.0 / 60.0);
+        }
+        assert!(flyer.ang_z.is_finite() && flyer.ang_z_vel.is_finite());
+        assert!(flyer.ang_z > 0.0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn cruising_holds_speed_and_height() {
+        let (speed, _, vertical) = aerodynamic_step(10.0, 0.0, 0.0, 0.0, 10.0, &crow(), 0.1);
+        assert!((speed - 10.0).abs() < 1e-4);
+        assert_eq!(vertical, 0.0);
+    }
+
+    #[test]
+    fn climbing_costs_speed_and_diving_gains_it() {
+        let gliding = FlyerProps { thrust_max: 0.0, ..crow() };
+        let (climb, _, _) = aerodynamic_step(10.0, 0.0, 0.3, 0.0, 10.0, &gliding, 0.1);
+        let (level, _, _) = aerodynamic_step(10.0, 0.0, 0.0, 0.0, 10.0, &gliding, 0.1);
+        let (dive, _, _) = aerodynamic_step(10.0, 0.0, -0.3, 0.0, 10.0, &gliding, 0.1);
+        assert!(climb < level);
+        assert!(level < 10.0);
+        assert!(dive > 10.0);
+    }
+
+    #[test]
+    fn too_slow_or_steep_a_bank_sinks() {
+        let (_, _, stalled) = aerodynamic_step(2.0, 0.0, 0.0, 0.0, 2.0, &crow(), 0.1);
+        assert!(stalled < 0.0);
+
+        let (_, _, banked) = aerodynamic_step(7.0, 0.0, 0.0, 1.4, 7.0, &crow(), 0.1);
+        assert!(banked < 0.0);
+
+        // With lift to spare, a sink is stopped.
+        let (_, _, recovering) = aerodynamic_step(15.0, -0.5, 0.0, 0.0, 15.0, &crow(), 0.1);
+        assert!(recovering > -0.5 && recovering <= 0.0);
+    }
+}
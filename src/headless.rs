@@ -0,0 +1,115 @@
+use std::time::Duration;
+use crate::boids;
+use boids::*;
+use crate::observe;
+use observe::*;
+use crate::density;
+use density::*;
+use crate::velocitate;
+use velocitate::*;
+use crate::steering;
+use steering::*;
+use crate::bounds;
+use bounds::*;
+use crate::flight;
+use flight::*;
+
+use bevy::{
+    app::{AppExit, ScheduleRunnerSettings},
+    log::LogPlugin,
+    prelude::*,
+};
+use rand::prelude::*;
+
+// How long to run for.
+struct RunFor {
+    seconds: f32,
+}
+
+// No window and no models: just the flock, for gathering densities
+// (written to crow_density.csv as it goes and when it's done) without
+// having to sit and watch.
+pub fn start_headless(seconds: f32) {
+
+    // The same bounds as bev4.
+    let dem_bounds = Bounds::new(
+        20.,
+        0.,
+        1000.,
+        0.,
+        500.,
+        0.,
+        1000.,
+        20.,
+    );
+
+    App::new()
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(1.0 / 60.0)))
+        .add_plugins(MinimalPlugins)
+        // So we hear about it if the density can't be written.
+        .add_plugin(LogPlugin)
+        .add_plugin(JayObserve {
+            backend: IndexBackend::sparse_grid_for(&dem_bounds),
+        })
+        .add_plugin(JayDensity)
+        .insert_resource(DensityExport {
+            path: Some("crow_density.csv".into()),
+            ..default()
+        })
+        .add_plugin(JayBoids)
+        .add_plugin(JaySteering)
+        .add_plugin(Flight)
+        .add_plugin(JayVelocitate)
+        .insert_resource(dem_bounds)
+        .insert_resource(RunFor { seconds })
+        .add_startup_system(startup)
+        .add_system(stop_system)
+        .run();
+}
+
+fn stop_system(
+    time: Res<Time>,
+    run_for: Res<RunFor>,
+    mut exits: EventWriter<AppExit>,
+) {
+    if time.seconds_since_startup() as f32 >= run_for.seconds {
+        exits.send(AppExit);
+    }
+}
+
+fn startup(
+    mut commands: Commands,
+    bounds: Res<Bounds>,
+) {
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..300
+    {
+        let x = rng.gen::<f32>() * bounds.x_size + bounds.x_min;
+        let y = rng.gen::<f32>() * bounds.y_size + bounds.y_min;
+        let z = rng.gen::<f32>() * bounds.z_size + bounds.z_min;
+        let rot = Quat::from_rotation_y(rng.gen::<f32>() * std::f32::consts::TAU);
+
+        commands.spawn_bundle((
+            Transform::from_xyz(x, y, z).with_rotation(rot),
+            GlobalTransform::default(),
+            Observable {
+                view_forward: Vec3::Z,
+                ..default()
+            },
+            Velocitator {
+                velocity: rot * Vec3::Z * 50.,
+                max_speed: rng.gen::<f32>() * 5.0 + 50.,
+            },
+            Separation {
+                separation_factor: Vec3::ZERO,
+            },
+            Alignment {
+                alignment_factor: Vec3::ZERO,
+            },
+            Cohesion {
+                cohesion_factor: Vec3::ZERO,
+            },
+        ));
+    }
+}
@@ -72,7 +72,8 @@ pub fn smooth_damp(
     if (original_to - current > 0.0) == (output > original_to)
     {
         output = original_to;
-        velocity_new = (output - original_to) / delta_time;
+        // A frame with no time in it (like bevy's first) would make this 0/0.
+        velocity_new = if delta_time > 0.0 { (output - original_to) / delta_time } else { 0.0 };
     }
 
     (output, velocity_new)
@@ -99,6 +100,15 @@ pub fn smooth_damp_angle(
         delta_time);
 }
 
+/// The bank angle for a coordinated turn: the roll at which lift balances
+/// gravity while turning at `yaw_rate` (radians per second) at `speed`.
+/// Turning left (positive yaw rate) banks left (positive roll about Z).
+pub fn coordinated_bank_angle(speed: f32, yaw_rate: f32, gravity: f32) -> f32
+{
+    if gravity <= 0.0 { return 0.0; }
+    (speed * yaw_rate / gravity).atan()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -328,4 +338,31 @@ mod tests {
             assert!(approx_eq_eps(dirc1.z, dirc2.z, eps));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn coordinated_bank_angle_a() {
+        assert!(approx_eq(coordinated_bank_angle(10.0, 0.0, 9.81), 0.0));
+        assert!(approx_eq(coordinated_bank_angle(0.0, 2.0, 9.81), 0.0));
+    }
+
+    // v * w = g banks 45 degrees, left for a left turn and right for a right
+    #[test]
+    fn coordinated_bank_angle_b() {
+        let left = coordinated_bank_angle(9.81, 1.0, 9.81);
+        let right = coordinated_bank_angle(9.81, -1.0, 9.81);
+        assert!(approx_eq_eps(left, PI * 0.25, 0.00001));
+        assert!(left > 0.0);
+        assert!(approx_eq_eps(right, -PI * 0.25, 0.00001));
+        assert!(right < 0.0);
+    }
+
+    // positive roll about Z lifts the right wing, banking into a left turn
+    #[test]
+    fn coordinated_bank_angle_c() {
+        let bank = coordinated_bank_angle(20.0, 1.0, 9.81);
+        let rot = Quat::from_euler(EulerRot::YXZ, 0.0, 0.0, bank);
+        let right_wing = rot * Vec3::X;
+        assert!(right_wing.y > 0.0);
+        assert!(bank < PI * 0.5);
+    }
+}
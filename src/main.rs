@@ -1,13 +1,32 @@
+mod bev4;
 mod bev5;
+mod headless;
 mod anim;
 mod boids;
 mod observe;
+mod spatial;
+mod occlusion;
+mod density;
 mod velocitate;
+mod steering;
+mod avoidance;
+mod terrain;
+mod predation;
+mod metrics;
+mod wind;
+mod altitude;
 mod bounds;
 mod flight;
 mod jaymath;
 
 fn main() {
-    bev5::start_bevy();
+    // `headless [seconds]` runs the flock with no window, for gathering data.
+    // `bev4` runs the full flock scene: boids flying crows, a raptor, wind and trees.
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("headless") => headless::start_headless(
+            args.get(2).and_then(|seconds| seconds.parse().ok()).unwrap_or(120.0)),
+        Some("bev4") => bev4::start_bevy(),
+        _ => bev5::start_bevy(),
+    }
 }
-
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use bevy::{
+    prelude::*,
+};
+use bevy_inspector_egui::Inspectable;
+
+use crate::observe;
+use observe::*;
+use crate::velocitate;
+use velocitate::*;
+use crate::boids;
+use boids::*;
+use crate::bounds;
+use bounds::*;
+
+// Our own plugin. Works out how orderly the flock is each tick, for judging
+// parameter changes by numbers rather than by eye. Add an
+// InspectorPlugin::<FlockMetrics> to watch them in the editor.
+pub struct JayMetrics;
+
+impl Plugin for JayMetrics {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<FlockMetrics>()
+            .add_system(flock_metrics_system
+                .after(ObserveSystem::UpdateObserved)
+                .after(ObserveSystem::Occlusion));
+    }
+}
+
+#[derive(Inspectable, Debug)]
+pub struct FlockMetrics {
+    pub count: usize,
+    // 1 when everyone's heading the same way, near 0 when it's a jumble.
+    pub polarization: f32,
+    // 1 when everyone's circling the centre of the flock, as in a mill.
+    pub milling: f32,
+    // Distance from each bird to its nearest flockmate.
+    pub nearest_neighbour_mean: f32,
+    pub nearest_neighbour_min: f32,
+    pub nearest_neighbour_max: f32,
+    // Counts of nearest-neighbour distances in bins this wide; the last bin takes everything beyond.
+    pub histogram_bin_width: f32,
+    pub nearest_neighbour_histogram: Vec<u32>,
+    // Groups of birds connected by who sees whom.
+    pub groups: usize,
+    pub largest_group: usize,
+    // Furthest anyone is from the middle of the flock, and the size of the box around it.
+    pub extent_radius: f32,
+    pub extent_size: Vec3,
+}
+
+impl Default for FlockMetrics {
+    fn default() -> Self {
+        FlockMetrics {
+            count: 0,
+            polarization: 0.0,
+            milling: 0.0,
+            nearest_neighbour_mean: 0.0,
+            nearest_neighbour_min: 0.0,
+            nearest_neighbour_max: 0.0,
+            histogram_bin_width: 2.0,
+            nearest_neighbour_histogram: vec![0; 10],
+            groups: 0,
+            largest_group: 0,
+            extent_radius: 0.0,
+            extent_size: Vec3::ZERO,
+        }
+    }
+}
+
+/// How aligned the headings are: the length of their average direction.
+pub fn polarization(velocities: &[Vec3]) -> f32
+{
+    if velocities.is_empty() { return 0.0; }
+
+    let sum = velocities.iter().fold(Vec3::ZERO, |sum, v| sum + v.normalize_or_zero());
+    sum.length() / velocities.len() as f32
+}
+
+/// How much everyone's going round the centre together: the normalised
+/// angular momentum about the centroid.
+pub fn milling(positions: &[Vec3], velocities: &[Vec3]) -> f32
+{
+    if positions.is_empty() { return 0.0; }
+
+    let centre = positions.iter().fold(Vec3::ZERO, |sum, p| sum + *p) / positions.len() as f32;
+    let momentum = positions.iter().zip(velocities.iter())
+        .fold(Vec3::ZERO, |sum, (p, v)| sum + (*p - centre).normalize_or_zero().cross(v.normalize_or_zero()));
+    momentum.length() / positions.len() as f32
+}
+
+/// Positions as one piece in a wrapped world: everyone's placed by the
+/// shortest way from the first of them, so a flock straddling a seam
+/// isn't split across the box.
+pub fn unwrap_positions(positions: &[Vec3], wrap: Option<Wrap>) -> Vec<Vec3>
+{
+    let (wrap, reference) = match (wrap, positions.first()) {
+        (Some(wrap), Some(reference)) if wrap.any() => (wrap, *reference),
+        _ => return positions.to_vec(),
+    };
+    positions.iter().map(|p| reference + wrap.offset(reference, *p)).collect()
+}
+
+fn find_root(parents: &mut [usize], i: usize) -> usize
+{
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    // Point everything on the way straight at the root, for next time.
+    let mut i = i;
+    while parents[i] != root {
+        let next = parents[i];
+        parents[i] = root;
+        i = next;
+    }
+    root
+}
+
+/// Sizes of the connected groups among `count` things joined by `links`.
+pub fn group_sizes(count: usize, links: impl Iterator<Item = (usize, usize)>) -> Vec<usize>
+{
+    let mut parents: Vec<usize> = (0..count).collect();
+    for (a, b) in links {
+        let (root_a, root_b) = (find_root(&mut parents, a), find_root(&mut parents, b));
+        if root_a != root_b {
+            parents[root_a] = root_b;
+        }
+    }
+
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    for i in 0..count {
+        *sizes.entry(find_root(&mut parents, i)).or_insert(0) += 1;
+    }
+    sizes.into_values().collect()
+}
+
+impl FlockMetrics {
+    fn update_nearest_neighbours(&mut self, distances: &[f32])
+    {
+        for count in self.nearest_neighbour_histogram.iter_mut() {
+            *count = 0;
+        }
+
+        if distances.is_empty() {
+            self.nearest_neighbour_mean = 0.0;
+            self.nearest_neighbour_min = 0.0;
+            self.nearest_neighbour_max = 0.0;
+            return;
+        }
+
+        self.nearest_neighbour_mean = distances.iter().sum::<f32>() / distances.len() as f32;
+        self.nearest_neighbour_min = distances.iter().copied().fold(f32::INFINITY, f32::min);
+        self.nearest_neighbour_max = distances.iter().copied().fold(0.0, f32::max);
+
+        let bins = self.nearest_neighbour_histogram.len();
+        if bins == 0 || self.histogram_bin_width <= 0. { return; }
+        for distance in distances {
+            let bin = ((distance / self.histogram_bin_width) as usize).min(bins - 1);
+            self.nearest_neighbour_histogram[bin] += 1;
+        }
+    }
+
+    fn update_extent(&mut self, positions: &[Vec3])
+    {
+        if positions.is_empty() {
+            self.extent_radius = 0.0;
+            self.extent_size = Vec3::ZERO;
+            return;
+        }
+
+        let centre = positions.iter().fold(Vec3::ZERO, |sum, p| sum + *p) / positions.len() as f32;
+        self.extent_radius = positions.iter().map(|p| p.distance(centre)).fold(0.0, f32::max);
+
+        let min = positions.iter().fold(Vec3::splat(f32::INFINITY), |min, p| min.min(*p));
+        let max = positions.iter().fold(Vec3::splat(f32::NEG_INFINITY), |max, p| max.max(*p));
+        self.extent_size = max - min;
+    }
+}
+
+fn flock_metrics_system(
+    mut metrics: ResMut<FlockMetrics>,
+    stuff: Res<StuffsToObserve>,
+    bounds: Option<Res<Bounds>>,
+    // Anything that flocks counts; predators and the like don't.
+    query: Query<(Entity, &Transform, &Velocitator, Option<&Observable>), With<Alignment>>,
+)
+{
+    let mut index = HashMap::new();
+    let mut positions = Vec::new();
+    let mut velocities = Vec::new();
+    for (entity, transform, velocitator, _) in query.iter() {
+        index.insert(entity, positions.len());
+        positions.push(transform.translation);
+        velocities.push(velocitator.velocity);
+    }
+
+    metrics.count = positions.len();
+    metrics.polarization = polarization(&velocities);
+    let unwrapped = unwrap_positions(&positions, bounds.map(|bounds| bounds.wrap()));
+    metrics.milling = milling(&unwrapped, &velocities);
+    metrics.update_extent(&unwrapped);
+
+    // Nearest flockmates, and who's linked to whom by sight.
+    let mut distances = Vec::new();
+    let mut links = Vec::new();
+    for (entity, transform, _, observable) in query.iter() {
+        let observable = match observable {
+            Some(observable) => observable,
+            None => continue,
+        };
+
+        if let Some(nearest) = stuff.k_nearest(transform.translation, 1, observable.seen_on, Some(entity)).first() {
+            distances.push(nearest.distance);
+        }
+
+        let us = index[&entity];
+        for neighbour in observable.observed.iter() {
+            if observable.seen_on & neighbour.seen_on == 0 { continue; }
+            if let Some(them) = index.get(&neighbour.entity) {
+                links.push((us, *them));
+            }
+        }
+    }
+    metrics.update_nearest_neighbours(&distances);
+
+    let groups = group_sizes(positions.len(), links.into_iter());
+    metrics.groups = groups.len();
+    metrics.largest_group = groups.into_iter().max().unwrap_or(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polarization_of_aligned_and_opposed() {
+        assert!((polarization(&[Vec3::X, Vec3::X * 5.0]) - 1.0).abs() < 1e-6);
+        assert!(polarization(&[Vec3::X, -Vec3::X]) < 1e-6);
+    }
+
+    #[test]
+    fn milling_when_circling() {
+        let positions = [Vec3::X, Vec3::Z, -Vec3::X, -Vec3::Z];
+        let circling: Vec<Vec3> = positions.iter().map(|p| p.cross(Vec3::Y)).collect();
+        assert!((milling(&positions, &circling) - 1.0).abs() < 1e-6);
+        assert!(polarization(&circling) < 1e-6);
+
+        let straight = [Vec3::X; 4];
+        assert!(milling(&positions, &straight) < 1e-6);
+    }
+
+    #[test]
+    fn unwrapping_keeps_a_flock_on_a_seam_together() {
+        let bounds = Bounds::new(10., 0., 100., 0., 100., 0., 100., 0.).wrapped(true, false, false);
+        let positions = [Vec3::new(99.0, 50.0, 50.0), Vec3::new(1.0, 50.0, 50.0)];
+
+        let unwrapped = unwrap_positions(&positions, Some(bounds.wrap()));
+        assert!((unwrapped[1] - Vec3::new(101.0, 50.0, 50.0)).length() < 1e-4);
+
+        let mut metrics = FlockMetrics::default();
+        metrics.update_extent(&unwrapped);
+        assert!((metrics.extent_size.x - 2.0).abs() < 1e-4);
+
+        // Without wrapping they're as far apart as they look.
+        assert_eq!(unwrap_positions(&positions, None), positions.to_vec());
+    }
+
+    #[test]
+    fn groups_are_connected_components() {
+        let mut sizes = group_sizes(6, vec![(0, 1), (1, 2), (4, 3)].into_iter());
+        sizes.sort_unstable();
+        assert_eq!(sizes, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn nearest_neighbour_histogram() {
+        let mut metrics = FlockMetrics::default();
+        metrics.update_nearest_neighbours(&[1.0, 1.5, 3.0, 100.0]);
+
+        assert_eq!(metrics.nearest_neighbour_histogram[0], 2);
+        assert_eq!(metrics.nearest_neighbour_histogram[1], 1);
+        assert_eq!(metrics.nearest_neighbour_histogram[9], 1);
+        assert_eq!(metrics.nearest_neighbour_min, 1.0);
+        assert_eq!(metrics.nearest_neighbour_max, 100.0);
+    }
+}
@@ -1,152 +1,682 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
 use bevy::{
+    ecs::system::SystemParam,
     prelude::*,
+    tasks::ComputeTaskPool,
 };
 
+use crate::spatial;
+use spatial::*;
+use crate::bounds;
+use bounds::*;
+use crate::velocitate;
+use velocitate::*;
+
 // Our own plugin:
-pub struct JayObserve;
+pub struct JayObserve {
+    pub backend: IndexBackend,
+}
+
+impl Default for JayObserve {
+    fn default() -> Self {
+        JayObserve {
+            // Only shuffles things between cells when they cross into another.
+            // The k-d tree re-hangs anything that leaves its node's box, which a
+            // moving flock's leaves do all the time.
+            backend: IndexBackend::SparseGrid { cell_size: 20.0 },
+        }
+    }
+}
 
 impl Plugin for JayObserve {
     fn build(&self, app: &mut App) {
+        // The index is brought up to date once everything's moved for the
+        // frame, ready for looking about at the start of the next.
         app
-            .add_system(observation_system_update_cells)
-            .add_system(observation_system_update_observed.after(observation_system_update_cells))
-            .add_system(observation_system_update_hashmap.after(observation_system_update_observed));
+            .insert_resource(StuffsToObserve::new(self.backend))
+            .add_system_to_stage(CoreStage::PostUpdate, observation_system_update_index.label(ObserveSystem::UpdateIndex))
+            .add_system(observation_system_update_observed.label(ObserveSystem::UpdateObserved))
+            .add_system(perception_memory_system
+                .label(ObserveSystem::Memory)
+                .after(ObserveSystem::UpdateObserved)
+                .after(ObserveSystem::Occlusion));
     }
 }
 
-#[derive(Component, Debug, Default)]
+// So other plugins can slot in around observation.
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ObserveSystem {
+    UpdateIndex,
+    UpdateObserved,
+    Occlusion,
+    Memory,
+}
+
+// Observation layers are bits in a u32 mask. Scenes pick their own meanings
+// (one per species, predators, ...); these two are just handy.
+pub const LAYER_DEFAULT: u32 = 1;
+pub const LAYER_ALL: u32 = u32::MAX;
+
+#[derive(Component, Debug)]
 pub struct Observable {
-    pub cell: usize,
-    pub observed: Vec<Entity>,
+    // The layers we look at.
+    pub sees: u32,
+    // The layers others can find us on.
+    pub seen_on: u32,
+    // How far away we notice other observables.
+    pub view_range: f32,
+    // Half the angle of the cone we can see, around view_forward. PI sees all around.
+    pub view_half_angle: f32,
+    // Half the angle of a blind sector directly behind us. Zero for none.
+    pub blind_rear_half_angle: f32,
+    // Which way we look, in our local space.
+    pub view_forward: Vec3,
+    // Everything we can see, nearest first.
+    pub observed: Vec<Neighbour>,
+}
+
+impl Default for Observable {
+    fn default() -> Self {
+        Observable {
+            sees: LAYER_DEFAULT,
+            seen_on: LAYER_DEFAULT,
+            view_range: 20.0,
+            view_half_angle: PI,
+            blind_rear_half_angle: 0.0,
+            view_forward: -Vec3::Z,
+            observed: Vec::new(),
+        }
+    }
+}
+
+impl Observable {
+    /// Whether something at `offset` from us falls within our view cone and
+    /// outside the blind sector, given the way we're facing (in world space).
+    pub fn can_see(&self, forward: Vec3, offset: Vec3) -> bool
+    {
+        let forward = forward.normalize_or_zero();
+        let direction = offset.normalize_or_zero();
+        if forward == Vec3::ZERO || direction == Vec3::ZERO
+        { return true; }
+
+        let cos_from_front = forward.dot(direction);
+
+        if self.view_half_angle < PI && cos_from_front < self.view_half_angle.cos()
+        { return false; }
+
+        if self.blind_rear_half_angle > 0.0 && -cos_from_front > self.blind_rear_half_angle.cos()
+        { return false; }
+
+        true
+    }
+}
+
+// Another observable as seen from some position: everything steering needs
+// to know about it, so nobody has to go and look it up again.
+#[derive(Clone, Copy, Debug)]
+pub struct Neighbour {
+    pub entity: Entity,
+    // From us to them.
+    pub offset: Vec3,
+    pub distance: f32,
+    // How they're moving, if they're a Velocitator.
+    pub velocity: Option<Vec3>,
+    // The layers they're seen on.
+    pub seen_on: u32,
+}
+
+// What an observer last knew about something it saw.
+#[derive(Clone, Copy, Debug)]
+pub struct Memory {
+    pub last_position: Vec3,
+    pub last_velocity: Vec3,
+    // Seconds since startup.
+    pub seen_at: f64,
+}
+
+// Lets an observer keep track of things after they drop out of view.
+// Anything not seen for `duration` seconds is forgotten.
+#[derive(Component, Debug)]
+pub struct PerceptionMemory {
+    pub duration: f32,
+    pub remembered: HashMap<Entity, Memory>,
+}
+
+impl Default for PerceptionMemory {
+    fn default() -> Self {
+        PerceptionMemory::new(3.0)
+    }
 }
 
-// A resource which collects observable thingies by spatial hashing.
+impl PerceptionMemory {
+    pub fn new(duration: f32) -> PerceptionMemory {
+        PerceptionMemory {
+            duration,
+            remembered: HashMap::new(),
+        }
+    }
+
+    pub fn remember(&mut self, entity: Entity, position: Vec3, velocity: Vec3, now: f64)
+    {
+        self.remembered.insert(entity, Memory {
+            last_position: position,
+            last_velocity: velocity,
+            seen_at: now,
+        });
+    }
+
+    pub fn forget_stale(&mut self, now: f64)
+    {
+        let duration = self.duration as f64;
+        self.remembered.retain(|_, memory| now - memory.seen_at <= duration);
+    }
+
+    /// Where we'd guess something is now, if it kept going the way it was.
+    pub fn predicted_position(&self, entity: Entity, now: f64) -> Option<Vec3>
+    {
+        self.remembered.get(&entity).map(|memory| {
+            memory.last_position + memory.last_velocity * (now - memory.seen_at) as f32
+        })
+    }
+
+    /// Things we remember but didn't see just now.
+    pub fn out_of_view(&self, now: f64) -> impl Iterator<Item = (&Entity, &Memory)>
+    {
+        self.remembered.iter().filter(move |(_, memory)| memory.seen_at < now)
+    }
+}
+
+// A resource which collects observable thingies in spatial indices, one per layer.
 pub struct StuffsToObserve {
-    stuff: Vec<Vec<Entity>>,
-    cell_size: f32,
-    width: usize,
-    depth: usize,
+    backend: IndexBackend,
+    layers: Vec<Box<dyn SpatialIndex>>,
+    // Where each entity is currently indexed, and on which layers.
+    tracked: HashMap<Entity, Tracked>,
+    // Set when Bounds wraps around, so neighbours are found across the seams.
+    wrap: Option<Wrap>,
+}
+
+struct Tracked {
+    pos: Vec3,
+    seen_on: u32,
+    velocity: Option<Vec3>,
+}
+
+// What's to be done about one observable that's changed. Working it out only
+// reads the index, so lots can be worked out at once; applying it can't be.
+struct Placement {
+    entity: Entity,
+    pos: Vec3,
+    seen_on: u32,
+    velocity: Option<Vec3>,
+    how: Move,
+}
+
+enum Move {
+    // Where it is hasn't changed, only how it's moving.
+    Still,
+    // From here, staying in the same cells, so just updating where it is.
+    InPlace(Vec3),
+    // From here, into other cells.
+    Across(Vec3),
+    // New to us, or onto other layers.
+    Reindex,
 }
 
 impl StuffsToObserve {
-    pub fn new(width: usize, depth: usize, cell_size: f32) -> StuffsToObserve {
-        let mut stuff = Vec::new();
-        let size = width * depth;
-        for _ in 0..size {
-            stuff.push(Vec::new());
-        }
+    pub fn new(backend: IndexBackend) -> StuffsToObserve {
         StuffsToObserve {
-            stuff,
-            cell_size,
-            width,
-            depth,
+            backend,
+            layers: Vec::new(),
+            tracked: HashMap::new(),
+            wrap: None,
         }
     }
-}
 
-impl StuffsToObserve {
-    fn collect_cells(&self, cell: usize) -> Vec<usize>
+    // Indices are only made for layers somebody is actually seen on.
+    fn ensure_layers(&mut self, mask: u32)
     {
-        let mut all_cells = Vec::new();
+        let needed = (32 - mask.leading_zeros()) as usize;
+        while self.layers.len() < needed {
+            self.layers.push(self.backend.build());
+        }
+    }
 
-        all_cells.push(cell);
+    // Work out what putting an entity where it now is takes, if anything.
+    fn plan(&self, entity: Entity, pos: Vec3, seen_on: u32, velocity: Option<Vec3>) -> Option<Placement>
+    {
+        let how = match self.tracked.get(&entity) {
+            Some(tracked) if tracked.seen_on == seen_on => {
+                let from = tracked.pos;
+                if from == pos {
+                    if tracked.velocity == velocity { return None; }
+                    Move::Still
+                } else if self.layer_indices(seen_on).all(|index| index.stays_put(entity, from, pos)) {
+                    Move::InPlace(from)
+                } else {
+                    Move::Across(from)
+                }
+            }
+            _ => Move::Reindex,
+        };
 
-        let w = self.width as isize;
+        Some(Placement {
+            entity,
+            pos,
+            seen_on,
+            velocity,
+            how,
+        })
+    }
 
-        // the eight surrounding cells
-        let cell_r = cell as isize + 1;
-        let cell_l = cell as isize - 1;
-        let cell_u = cell as isize + w;
-        let cell_d = cell as isize - w;
-        let cell_ur = cell as isize + w + 1;
-        let cell_ul = cell as isize + w - 1;
-        let cell_dr = cell as isize - w + 1;
-        let cell_dl = cell as isize - w - 1;
+    // Move an entity as planned, only in the layers it's on.
+    fn apply(&mut self, placement: Placement)
+    {
+        let Placement { entity, pos, seen_on, velocity, how } = placement;
+        if let Move::Reindex = how {
+            self.forget(entity);
+            self.ensure_layers(seen_on);
+        }
 
-        if ind_valid(&self.stuff, cell_l)
-        { all_cells.push(cell_l as usize); }
+        for (layer, index) in self.layers.iter_mut().enumerate() {
+            if seen_on & (1 << layer) == 0 { continue; }
+            match how {
+                Move::Still => {}
+                Move::InPlace(from) => index.move_in_place(entity, from, pos),
+                Move::Across(from) => index.relocate(entity, from, pos),
+                Move::Reindex => index.insert(entity, pos),
+            }
+        }
+        self.tracked.insert(entity, Tracked {
+            pos,
+            seen_on,
+            velocity,
+        });
+    }
 
-        if ind_valid(&self.stuff, cell_r)
-        { all_cells.push(cell_r as usize); }
+    // Put an entity where it now is.
+    #[cfg(test)]
+    fn place(&mut self, entity: Entity, pos: Vec3, seen_on: u32, velocity: Option<Vec3>)
+    {
+        if let Some(placement) = self.plan(entity, pos, seen_on, velocity) {
+            self.apply(placement);
+        }
+    }
 
-        if ind_valid(&self.stuff, cell_u)
-        { all_cells.push(cell_u as usize); }
+    fn forget(&mut self, entity: Entity)
+    {
+        if let Some(tracked) = self.tracked.remove(&entity) {
+            for (layer, index) in self.layers.iter_mut().enumerate() {
+                if tracked.seen_on & (1 << layer) != 0 {
+                    index.remove(entity, tracked.pos);
+                }
+            }
+        }
+    }
 
-        if ind_valid(&self.stuff, cell_d)
-        { all_cells.push(cell_d as usize); }
+    // Fill in what the index doesn't know.
+    fn fill_tracked(&self, found: &mut Vec<Neighbour>)
+    {
+        for n in found.iter_mut() {
+            if let Some(tracked) = self.tracked.get(&n.entity) {
+                n.seen_on = tracked.seen_on;
+                n.velocity = tracked.velocity;
+            }
+        }
+    }
 
-        if ind_valid(&self.stuff, cell_ur)
-        { all_cells.push(cell_ur as usize); }
+    fn refresh(&mut self)
+    {
+        for index in self.layers.iter_mut() {
+            index.refresh();
+        }
+    }
 
-        if ind_valid(&self.stuff, cell_ul)
-        { all_cells.push(cell_ul as usize); }
+    fn layer_indices(&self, mask: u32) -> impl Iterator<Item = &dyn SpatialIndex>
+    {
+        self.layers.iter()
+            .enumerate()
+            .filter(move |(layer, _)| mask & (1 << *layer) != 0)
+            .map(|(_, index)| index.as_ref())
+    }
+
+    // Where to look from so that things within `reach` across a seam are
+    // found too. Looking from a shifted copy of `pos` gives offsets that
+    // already take the short way round.
+    fn images(&self, pos: Vec3, reach: f32) -> Vec<Vec3>
+    {
+        let mut images = vec![pos];
+        let wrap = match self.wrap {
+            Some(wrap) => wrap,
+            None => return images,
+        };
+
+        for axis in 0..3 {
+            if !wrap.axes[axis] { continue; }
+
+            let size = wrap.size[axis];
+            let near_min = pos[axis] - wrap.min[axis] < reach;
+            let near_max = wrap.min[axis] + size - pos[axis] < reach;
+
+            let mut shifted = Vec::new();
+            for image in images.iter() {
+                if near_min {
+                    let mut copy = *image;
+                    copy[axis] += size;
+                    shifted.push(copy);
+                }
+                if near_max {
+                    let mut copy = *image;
+                    copy[axis] -= size;
+                    shifted.push(copy);
+                }
+            }
+            images.extend(shifted);
+        }
+        images
+    }
 
-        if ind_valid(&self.stuff, cell_dr)
-        { all_cells.push(cell_dr as usize); }
+    /// Everything on any of `layers` within `radius` of `pos`, nearest first.
+    pub fn within_radius(&self, pos: Vec3, radius: f32, layers: u32, exclude: Option<Entity>) -> Vec<Neighbour>
+    {
+        let mut found = Vec::new();
+        for image in self.images(pos, radius) {
+            for index in self.layer_indices(layers) {
+                found.extend(index.within_radius(image, radius, exclude));
+            }
+        }
+        sort_and_dedup(&mut found);
+        self.fill_tracked(&mut found);
+        found
+    }
+
+    /// The `k` things on any of `layers` nearest to `pos`, nearest first.
+    pub fn k_nearest(&self, pos: Vec3, k: usize, layers: u32, exclude: Option<Entity>) -> Vec<Neighbour>
+    {
+        let mut found = Vec::new();
+        for index in self.layer_indices(layers) {
+            found.extend(index.k_nearest(pos, k, exclude));
+        }
+        sort_and_dedup(&mut found);
+        found.truncate(k);
 
-        if ind_valid(&self.stuff, cell_dl)
-        { all_cells.push(cell_dl as usize); }
+        // Anything across a seam has to be closer than what we've got so far.
+        if self.wrap.map_or(false, |wrap| wrap.any()) {
+            let reach = if found.len() < k { f32::INFINITY } else { found[k - 1].distance };
+            for image in self.images(pos, reach).into_iter().skip(1) {
+                for index in self.layer_indices(layers) {
+                    found.extend(index.k_nearest(image, k, exclude));
+                }
+            }
+            sort_and_dedup(&mut found);
+            found.truncate(k);
+        }
 
-        all_cells
+        self.fill_tracked(&mut found);
+        found
     }
 }
 
-fn ind_valid<T>(set: &Vec<T>, ind: isize) -> bool
+// Something on more than one layer (or seen both ways round a wrapped
+// world) can turn up more than once. Keep the nearest.
+fn sort_and_dedup(found: &mut Vec<Neighbour>)
 {
-    if ind < 0 { return false; }
-    if ind >= set.len() as isize { return false; }
+    found.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Ordering::Equal));
+    let mut seen = HashSet::new();
+    found.retain(|n| seen.insert(n.entity));
+}
 
-    true
+// Neighbour queries for any system that wants them.
+#[derive(SystemParam)]
+pub struct Neighbours<'w, 's> {
+    stuff_to_observe: Res<'w, StuffsToObserve>,
+    transforms: Query<'w, 's, (&'static Transform, Option<&'static Observable>)>,
 }
 
-// Our crude spatial-hash function.
-fn hash_function(pos: Vec3, cell_size: f32, width: usize, depth: usize) -> usize
-{
-    if cell_size <= 0.
-    { return 0; }
+impl<'w, 's> Neighbours<'w, 's> {
+    /// Everything `entity` could see within `radius` (not including itself), nearest first.
+    pub fn within_radius(&self, entity: Entity, radius: f32) -> Vec<Neighbour>
+    {
+        match self.transforms.get(entity) {
+            Ok((transform, obs)) => self.stuff_to_observe.within_radius(
+                transform.translation,
+                radius,
+                obs.map_or(LAYER_ALL, |obs| obs.sees),
+                Some(entity),
+            ),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// The `k` things nearest to `entity` that it could see (not including itself), nearest first.
+    pub fn k_nearest(&self, entity: Entity, k: usize) -> Vec<Neighbour>
+    {
+        match self.transforms.get(entity) {
+            Ok((transform, obs)) => self.stuff_to_observe.k_nearest(
+                transform.translation,
+                k,
+                obs.map_or(LAYER_ALL, |obs| obs.sees),
+                Some(entity),
+            ),
+            Err(_) => Vec::new(),
+        }
+    }
 
-    let x = (f32::floor(pos.x / cell_size) as usize).clamp(0, width - 1);
-    let y = (f32::floor(pos.y / cell_size) as usize).clamp(0, depth - 1);
-    x + y * width
-    // (f32::floor(pos.x / cell_size) + f32::floor(pos.y / cell_size) * width as f32) as usize
+    /// Everything on any of `layers` within `radius` of some point, nearest first.
+    pub fn within_radius_of(&self, pos: Vec3, radius: f32, layers: u32) -> Vec<Neighbour>
+    {
+        self.stuff_to_observe.within_radius(pos, radius, layers, None)
+    }
+
+    /// The `k` things on any of `layers` nearest to some point, nearest first.
+    pub fn k_nearest_to(&self, pos: Vec3, k: usize, layers: u32) -> Vec<Neighbour>
+    {
+        self.stuff_to_observe.k_nearest(pos, k, layers, None)
+    }
 }
 
-fn observation_system_update_cells(
-    stuff_to_observe: Res<StuffsToObserve>,
-    mut observables: Query<(&mut Observable, &Transform)>)
+// Only things that moved, changed layers or just turned up are touched, and
+// grids only shuffle them between cells when their cell changes.
+// (Observables are changed every frame when their observed list is filled
+// in, so anything that's where it was and on the same layers is left be.)
+// Which cells everything's moving to is worked out in parallel; only the
+// moves themselves are made one at a time, as the indices take one writer.
+fn observation_system_update_index(
+    pool: Res<ComputeTaskPool>,
+    bounds: Option<Res<Bounds>>,
+    mut stuff_to_observe: ResMut<StuffsToObserve>,
+    removed: RemovedComponents<Observable>,
+    observables: Query<(&Observable, &Transform, Option<&Velocitator>, Entity), Or<(Changed<Transform>, Changed<Observable>, Changed<Velocitator>)>>)
 {
-    for (mut obs, transform) in observables.iter_mut() {
-        obs.cell = hash_function(transform.translation, stuff_to_observe.cell_size, stuff_to_observe.width, stuff_to_observe.depth);
+    stuff_to_observe.wrap = bounds
+        .map(|bounds| bounds.wrap())
+        .filter(|wrap| wrap.any());
+
+    for entity in removed.iter() {
+        stuff_to_observe.forget(entity);
+    }
+
+    let changed: Vec<(Entity, Vec3, u32, Option<Vec3>)> = observables.iter()
+        .map(|(obs, transform, velocitator, entity)| (entity, transform.translation, obs.seen_on, velocitator.map(|v| v.velocity)))
+        .collect();
+
+    let stuff: &StuffsToObserve = &stuff_to_observe;
+    let placements = pool.scope(|scope| {
+        for batch in changed.chunks(OBSERVE_BATCH_SIZE) {
+            scope.spawn(async move {
+                batch.iter()
+                    .filter_map(|(entity, pos, seen_on, velocity)| stuff.plan(*entity, *pos, *seen_on, *velocity))
+                    .collect::<Vec<_>>()
+            });
+        }
+    });
+
+    for placement in placements.into_iter().flatten() {
+        stuff_to_observe.apply(placement);
     }
+    stuff_to_observe.refresh();
 }
 
+// How many observables each task works through.
+const OBSERVE_BATCH_SIZE: usize = 32;
+
 fn observation_system_update_observed(
+    pool: Res<ComputeTaskPool>,
     stuff_to_observe: Res<StuffsToObserve>,
-    mut observables: Query<&mut Observable>)
+    mut observables: Query<(&mut Observable, &Transform, Entity)>)
 {
-    for mut obs in observables.iter_mut() {
-        obs.observed.clear();
-        let near_cells = stuff_to_observe.collect_cells(obs.cell);
-        for near_cell in near_cells.iter()
-        {
-            for entity in stuff_to_observe.stuff[*near_cell].iter() {
-                obs.observed.push(*entity);
-            }
+    let stuff_to_observe: &StuffsToObserve = &stuff_to_observe;
+
+    observables.par_for_each_mut(&pool, OBSERVE_BATCH_SIZE, |(mut obs, transform, entity)| {
+        let mut observed = stuff_to_observe.within_radius(transform.translation, obs.view_range, obs.sees, Some(entity));
+
+        let forward = transform.rotation * obs.view_forward;
+        observed.retain(|n| obs.can_see(forward, n.offset));
+
+        obs.observed = observed;
+    });
+}
+
+fn perception_memory_system(
+    time: Res<Time>,
+    mut query: Query<(&mut PerceptionMemory, &Observable, &Transform)>,
+)
+{
+    let now = time.seconds_since_startup();
+    for (mut memory, obs, transform) in query.iter_mut() {
+        for n in obs.observed.iter() {
+            memory.remember(n.entity, transform.translation + n.offset, n.velocity.unwrap_or(Vec3::ZERO), now);
         }
+        memory.forget_stale(now);
     }
 }
 
-fn observation_system_update_hashmap(
-    mut stuff_to_observe: ResMut<StuffsToObserve>,
-    observables: Query<(&Observable, Entity)>)
-{
-    for thing in stuff_to_observe.stuff.iter_mut() {
-        thing.clear();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_see_all_around_by_default() {
+        let obs = Observable::default();
+        assert!(obs.can_see(-Vec3::Z, Vec3::Z));
+        assert!(obs.can_see(-Vec3::Z, Vec3::X));
     }
-    for (obs, entity) in observables.iter() {
-        if let Some(set) = stuff_to_observe.stuff.get_mut(obs.cell)
-        {
-            set.push(entity);
-        }
+
+    #[test]
+    fn can_see_only_inside_cone() {
+        let obs = Observable {
+            view_half_angle: PI * 0.25,
+            ..Default::default()
+        };
+        assert!(obs.can_see(-Vec3::Z, Vec3::new(0.5, 0.0, -1.0)));
+        assert!(!obs.can_see(-Vec3::Z, Vec3::X));
+        assert!(!obs.can_see(-Vec3::Z, Vec3::Z));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn cannot_see_into_blind_sector() {
+        let obs = Observable {
+            blind_rear_half_angle: PI * 0.25,
+            ..Default::default()
+        };
+        assert!(obs.can_see(-Vec3::Z, Vec3::X));
+        assert!(!obs.can_see(-Vec3::Z, Vec3::new(0.2, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn layers_are_kept_apart() {
+        let mut stuff = StuffsToObserve::new(IndexBackend::KdTree);
+        let crow = Entity::from_raw(0);
+        let hawk = Entity::from_raw(1);
+        let both = Entity::from_raw(2);
+        stuff.ensure_layers(0b11);
+        stuff.layers[0].rebuild(&[(crow, Vec3::X), (both, Vec3::Y)]);
+        stuff.layers[1].rebuild(&[(hawk, Vec3::Z), (both, Vec3::Y)]);
+
+        let found = stuff.within_radius(Vec3::ZERO, 5.0, 0b01, None);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|n| n.entity != hawk));
+
+        let found = stuff.within_radius(Vec3::ZERO, 5.0, 0b11, None);
+        assert_eq!(found.len(), 3);
+
+        let found = stuff.k_nearest(Vec3::ZERO, 2, 0b11, None);
+        assert_eq!(found.len(), 2);
+    }
+
+    #[test]
+    fn place_moves_between_layers() {
+        let mut stuff = StuffsToObserve::new(IndexBackend::SparseGrid { cell_size: 10.0 });
+        let crow = Entity::from_raw(0);
+
+        stuff.place(crow, Vec3::ZERO, 0b01, None);
+        stuff.place(crow, Vec3::X, 0b01, Some(Vec3::X * 2.0));
+        stuff.refresh();
+        let found = stuff.within_radius(Vec3::ZERO, 5.0, 0b01, None);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].velocity, Some(Vec3::X * 2.0));
+        assert_eq!(found[0].seen_on, 0b01);
+
+        // Staying put still takes the latest velocity.
+        stuff.place(crow, Vec3::X, 0b01, Some(Vec3::ZERO));
+        let found = stuff.within_radius(Vec3::ZERO, 5.0, 0b01, None);
+        assert_eq!(found[0].velocity, Some(Vec3::ZERO));
+
+        stuff.place(crow, Vec3::Y, 0b10, None);
+        stuff.refresh();
+        assert!(stuff.within_radius(Vec3::ZERO, 5.0, 0b01, None).is_empty());
+        assert_eq!(stuff.within_radius(Vec3::ZERO, 5.0, 0b10, None)[0].offset, Vec3::Y);
+
+        stuff.forget(crow);
+        stuff.refresh();
+        assert!(stuff.within_radius(Vec3::ZERO, 5.0, LAYER_ALL, None).is_empty());
+    }
+
+    #[test]
+    fn memory_predicts_and_decays() {
+        let mut memory = PerceptionMemory::new(2.0);
+        let hawk = Entity::from_raw(0);
+        memory.remember(hawk, Vec3::ZERO, Vec3::X, 1.0);
+
+        assert_eq!(memory.predicted_position(hawk, 1.5), Some(Vec3::X * 0.5));
+        assert_eq!(memory.out_of_view(1.0).count(), 0);
+        assert_eq!(memory.out_of_view(1.5).count(), 1);
+
+        memory.forget_stale(2.5);
+        assert!(memory.predicted_position(hawk, 2.5).is_some());
+        memory.forget_stale(3.5);
+        assert!(memory.predicted_position(hawk, 3.5).is_none());
+
+        // Not forgotten as soon as it's remembered.
+        let mut memory = PerceptionMemory::default();
+        memory.remember(hawk, Vec3::ZERO, Vec3::X, 1.0);
+        memory.forget_stale(2.0);
+        assert!(memory.predicted_position(hawk, 2.0).is_some());
+    }
+
+    #[test]
+    fn neighbours_are_found_across_seams() {
+        let bounds = Bounds::new(10., 0., 100., 0., 50., 0., 100., 10.).wrapped(true, false, true);
+        let mut stuff = StuffsToObserve::new(IndexBackend::SparseGrid { cell_size: 10.0 });
+        stuff.wrap = Some(bounds.wrap());
+
+        let left = Entity::from_raw(0);
+        let right = Entity::from_raw(1);
+        let corner = Entity::from_raw(2);
+        stuff.place(left, Vec3::new(2.0, 25.0, 50.0), LAYER_DEFAULT, None);
+        stuff.place(right, Vec3::new(97.0, 25.0, 50.0), LAYER_DEFAULT, None);
+        stuff.place(corner, Vec3::new(98.0, 25.0, 99.0), LAYER_DEFAULT, None);
+        stuff.refresh();
+
+        let found = stuff.within_radius(Vec3::new(2.0, 25.0, 50.0), 10.0, LAYER_ALL, Some(left));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].entity, right);
+        assert_eq!(found[0].offset, Vec3::new(-5.0, 0.0, 0.0));
+
+        let found = stuff.k_nearest(Vec3::new(1.0, 25.0, 1.0), 1, LAYER_ALL, None);
+        assert_eq!(found[0].entity, corner);
+        assert_eq!(found[0].offset, Vec3::new(-3.0, 0.0, -2.0));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::{HashMap, HashSet};
+use bevy::{
+    prelude::*,
+};
+use heron::rapier_plugin::PhysicsWorld;
+
+use crate::observe;
+use observe::*;
+
+// Our own plugin. Drops observed neighbours that are hidden behind colliders.
+// Needs heron's PhysicsPlugin.
+pub struct JayOcclusion;
+
+impl Plugin for JayOcclusion {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<OcclusionSettings>()
+            .init_resource::<OcclusionCache>()
+            .add_system(occlusion_system
+                .label(ObserveSystem::Occlusion)
+                .after(ObserveSystem::UpdateObserved));
+    }
+}
+
+pub struct OcclusionSettings {
+    // Line-of-sight checks are spread over frames; this caps how many we do in one.
+    pub max_rays_per_frame: usize,
+}
+
+impl Default for OcclusionSettings {
+    fn default() -> Self {
+        OcclusionSettings {
+            max_rays_per_frame: 500,
+        }
+    }
+}
+
+// Which pairs were last found to be blocked, and on which frame each pair was
+// last checked, so the ones gone longest without are checked first.
+// Pairs are stored lowest entity first since sight works both ways.
+#[derive(Default)]
+pub struct OcclusionCache {
+    blocked: HashSet<(Entity, Entity)>,
+    checked_on: HashMap<(Entity, Entity), u32>,
+    frame: u32,
+}
+
+impl OcclusionCache {
+    /// Whether the last look between the two found something in the way.
+    pub fn is_blocked(&self, a: Entity, b: Entity) -> bool
+    {
+        self.blocked.contains(&pair_key(a, b))
+    }
+}
+
+fn pair_key(a: Entity, b: Entity) -> (Entity, Entity)
+{
+    if a < b { (a, b) } else { (b, a) }
+}
+
+fn occlusion_system(
+    settings: Res<OcclusionSettings>,
+    mut cache: ResMut<OcclusionCache>,
+    physics_world: PhysicsWorld,
+    mut observables: Query<(&mut Observable, &Transform, Entity)>,
+)
+{
+    let cache = &mut *cache;
+    cache.frame += 1;
+
+    // Work out this frame's pairs, each looked at from whoever saw the other first.
+    let mut pairs = HashMap::new();
+    for (obs, transform, entity) in observables.iter() {
+        for neighbour in obs.observed.iter() {
+            pairs.entry(pair_key(entity, neighbour.entity))
+                .or_insert((entity, neighbour.entity, transform.translation, neighbour.offset));
+        }
+    }
+
+    // Check the batch that's gone longest unchecked (never checked counts as frame 0).
+    let mut stalest: Vec<((Entity, Entity), u32)> = pairs.keys()
+        .map(|key| (*key, cache.checked_on.get(key).copied().unwrap_or(0)))
+        .collect();
+    let rays = settings.max_rays_per_frame.min(stalest.len());
+    if rays < stalest.len() {
+        stalest.select_nth_unstable_by_key(rays, |(_, checked_on)| *checked_on);
+    }
+
+    for (key, _) in stalest.iter().take(rays) {
+        let (entity, other, from, offset) = pairs[key];
+        let hit = physics_world.ray_cast(from, offset, true);
+        let blocked = match hit {
+            Some(info) => info.entity != entity && info.entity != other,
+            None => false,
+        };
+
+        if blocked {
+            cache.blocked.insert(*key);
+        } else {
+            cache.blocked.remove(key);
+        }
+        cache.checked_on.insert(*key, cache.frame);
+    }
+
+    // Forget pairs that aren't near each other any more.
+    cache.blocked.retain(|key| pairs.contains_key(key));
+    cache.checked_on.retain(|key, _| pairs.contains_key(key));
+
+    if cache.blocked.is_empty() { return; }
+
+    let blocked = &cache.blocked;
+    for (mut obs, _, entity) in observables.iter_mut() {
+        obs.observed.retain(|n| !blocked.contains(&pair_key(entity, n.entity)));
+    }
+}
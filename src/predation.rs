@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use bevy::{
+    prelude::*,
+};
+
+use crate::observe;
+use observe::*;
+use crate::steering;
+use steering::*;
+use crate::boids;
+use boids::*;
+use crate::bounds;
+use bounds::*;
+
+// Our own plugin. Predators hunt whatever prey they can see, picking on the
+// most isolated; prey that see a predator, or a neighbour panicking, get
+// alarmed, flee and bunch up. Built on Pursue and Evade, so needs JaySteering.
+pub struct JayPredation;
+
+impl Plugin for JayPredation {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<CaptureEvent>()
+            .add_system_set(
+                SystemSet::new()
+                    .label(PredationSystem::Hunt)
+                    .after(ObserveSystem::UpdateObserved)
+                    .after(ObserveSystem::Occlusion)
+                    .before(SteeringSystem::Behaviours)
+                    .with_system(predator_hunt_system)
+                    .with_system(prey_alarm_system)
+            )
+            .add_system(predator_capture_system.after(PredationSystem::Hunt))
+            .add_system(prey_contract_system
+                .after(BoidsSystem::Rules)
+                .before(SteeringSystem::Combine));
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PredationSystem {
+    Hunt,
+}
+
+// Sent when a predator gets close enough to its target to catch it.
+// What happens to the prey is up to whoever's listening.
+#[derive(Debug)]
+pub struct CaptureEvent {
+    pub predator: Entity,
+    pub prey: Entity,
+}
+
+#[derive(Component, Debug)]
+pub struct Predator {
+    pub target: Option<Entity>,
+    pub capture_distance: f32,
+    // After a catch, take a break for this long.
+    pub rest_seconds: f32,
+    pub resting: f32,
+    pub pursue_weight: f32,
+}
+
+impl Default for Predator {
+    fn default() -> Self {
+        Predator {
+            target: None,
+            capture_distance: 3.0,
+            rest_seconds: 10.0,
+            resting: 0.0,
+            pursue_weight: 1.0,
+        }
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct Prey {
+    // From 0, calm, to 1, fleeing for our life.
+    pub alarm: f32,
+    // Who we're running from, if we know.
+    pub threat: Option<Entity>,
+    // How much alarm wears off per second.
+    pub alarm_decay: f32,
+    // How much of a neighbour's alarm we catch from them.
+    pub alarm_spread: f32,
+    pub panic_distance: f32,
+    pub flee_weight: f32,
+    // How much harder we cohere when fully alarmed.
+    pub contract: f32,
+}
+
+impl Default for Prey {
+    fn default() -> Self {
+        Prey {
+            alarm: 0.0,
+            threat: None,
+            alarm_decay: 0.25,
+            alarm_spread: 0.8,
+            panic_distance: 50.0,
+            flee_weight: 1.0,
+            contract: 2.0,
+        }
+    }
+}
+
+/// Of (candidate, how far it is from its own nearest flockmate, how far it is from us),
+/// the loneliest one, going for the nearest if there's a tie.
+pub fn most_isolated(candidates: impl Iterator<Item = (Entity, f32, f32)>) -> Option<Entity>
+{
+    let mut best: Option<(Entity, f32, f32)> = None;
+    for (entity, isolation, distance) in candidates {
+        let better = match best {
+            None => true,
+            Some((_, best_isolation, best_distance)) =>
+                isolation > best_isolation || (isolation == best_isolation && distance < best_distance),
+        };
+        if better {
+            best = Some((entity, isolation, distance));
+        }
+    }
+    best.map(|(entity, _, _)| entity)
+}
+
+/// Alarm after a `decay`'s worth of calming down, or as much as we caught from
+/// whatever alarmed us, whichever is more.
+pub fn spread_alarm(alarm: f32, decay: f32, caught: impl Iterator<Item = f32>) -> f32
+{
+    caught.fold((alarm - decay).max(0.0), f32::max).min(1.0)
+}
+
+// How far a prey animal is from its nearest flockmate.
+fn isolation(observable: &Observable) -> f32
+{
+    observable.observed.iter()
+        .find(|n| n.seen_on & observable.seen_on != 0)
+        .map_or(f32::INFINITY, |n| n.distance)
+}
+
+fn predator_hunt_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut predators: Query<(&mut Predator, &Observable, Option<&mut Pursue>, Entity)>,
+    prey: Query<&Observable, With<Prey>>,
+)
+{
+    for (mut predator, observable, pursue, entity) in predators.iter_mut() {
+        if predator.resting > 0. {
+            predator.resting -= time.delta_seconds();
+            predator.target = None;
+        } else {
+            // Stick with what we're chasing while we can still see it.
+            let still_seen = predator.target
+                .map_or(false, |target| observable.observed.iter().any(|n| n.entity == target));
+            if !still_seen {
+                predator.target = most_isolated(observable.observed.iter()
+                    .filter_map(|n| prey.get(n.entity).ok().map(|p| (n.entity, isolation(p), n.distance))));
+            }
+        }
+
+        match (predator.target, pursue) {
+            (Some(target), Some(mut pursue)) => {
+                pursue.target = target;
+                pursue.weight = predator.pursue_weight;
+            }
+            (Some(target), None) => {
+                commands.entity(entity).insert(Pursue {
+                    target,
+                    weight: predator.pursue_weight,
+                    pursue_factor: Vec3::ZERO,
+                });
+            }
+            (None, Some(_)) => {
+                commands.entity(entity).remove::<Pursue>();
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+fn predator_capture_system(
+    mut commands: Commands,
+    bounds: Option<Res<Bounds>>,
+    mut captures: EventWriter<CaptureEvent>,
+    mut predators: Query<(&mut Predator, &Transform, Entity)>,
+    prey: Query<&Transform, With<Prey>>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut predator, transform, entity) in predators.iter_mut() {
+        let target = match predator.target {
+            Some(target) => target,
+            None => continue,
+        };
+        let target_transform = match prey.get(target) {
+            Ok(target_transform) => target_transform,
+            Err(_) => continue,
+        };
+
+        let offset = match &wrap {
+            Some(wrap) => wrap.offset(transform.translation, target_transform.translation),
+            None => target_transform.translation - transform.translation,
+        };
+        if offset.length() > predator.capture_distance { continue; }
+
+        captures.send(CaptureEvent {
+            predator: entity,
+            prey: target,
+        });
+        predator.target = None;
+        predator.resting = predator.rest_seconds;
+        commands.entity(entity).remove::<Pursue>();
+    }
+}
+
+fn prey_alarm_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut prey: Query<(&mut Prey, &Observable, Option<&mut Evade>, Entity)>,
+    predators: Query<(), With<Predator>>,
+)
+{
+    // Everyone reacts to how alarmed their neighbours were last frame.
+    let alarms: HashMap<Entity, (f32, Option<Entity>)> = prey.iter()
+        .map(|(us, _, _, entity)| (entity, (us.alarm, us.threat)))
+        .collect();
+
+    for (mut us, observable, evade, entity) in prey.iter_mut() {
+        let mut threat = us.threat;
+        let mut caught = Vec::new();
+        for neighbour in observable.observed.iter() {
+            if predators.get(neighbour.entity).is_ok() {
+                caught.push(1.0);
+                threat = Some(neighbour.entity);
+            } else if let Some((alarm, their_threat)) = alarms.get(&neighbour.entity) {
+                caught.push(alarm * us.alarm_spread);
+                if threat.is_none() {
+                    threat = *their_threat;
+                }
+            }
+        }
+
+        us.alarm = spread_alarm(us.alarm, us.alarm_decay * time.delta_seconds(), caught.into_iter());
+        us.threat = if us.alarm > 0. { threat } else { None };
+
+        match (us.threat, evade) {
+            (Some(target), Some(mut evade)) => {
+                evade.target = target;
+                evade.panic_distance = us.panic_distance;
+                evade.weight = us.flee_weight * us.alarm;
+            }
+            (Some(target), None) => {
+                commands.entity(entity).insert(Evade {
+                    target,
+                    panic_distance: us.panic_distance,
+                    weight: us.flee_weight * us.alarm,
+                    evade_factor: Vec3::ZERO,
+                });
+            }
+            (None, Some(_)) => {
+                commands.entity(entity).remove::<Evade>();
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+// Alarmed flocks pull together; those close enough to the predator to
+// flee from it split away, so the flock contracts or splits around it.
+fn prey_contract_system(
+    mut query: Query<(&Prey, &mut Cohesion)>,
+)
+{
+    for (us, mut cohesion) in query.iter_mut() {
+        if us.alarm > 0. {
+            cohesion.cohesion_factor *= 1.0 + us.contract * us.alarm;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_loneliest_then_the_nearest() {
+        let (a, b, c) = (Entity::from_raw(0), Entity::from_raw(1), Entity::from_raw(2));
+        assert_eq!(most_isolated(vec![(a, 2.0, 10.0), (b, 8.0, 30.0), (c, 1.0, 5.0)].into_iter()), Some(b));
+        assert_eq!(most_isolated(vec![(a, 8.0, 10.0), (b, 8.0, 30.0)].into_iter()), Some(a));
+        assert_eq!(most_isolated(Vec::new().into_iter()), None);
+    }
+
+    #[test]
+    fn alarm_spreads_and_decays() {
+        assert_eq!(spread_alarm(0.0, 0.1, vec![1.0].into_iter()), 1.0);
+        assert_eq!(spread_alarm(0.0, 0.1, vec![0.4, 0.2].into_iter()), 0.4);
+        assert!((spread_alarm(0.5, 0.1, vec![0.2].into_iter()) - 0.4).abs() < 1e-6);
+        assert_eq!(spread_alarm(0.05, 0.1, Vec::new().into_iter()), 0.0);
+    }
+}
@@ -0,0 +1,1105 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use bevy::{
+    prelude::*,
+};
+
+use crate::observe::Neighbour;
+use crate::bounds::Bounds;
+
+// Something that can find observables near a point.
+pub trait SpatialIndex: Send + Sync {
+    fn clear(&mut self);
+
+    fn insert(&mut self, entity: Entity, pos: Vec3);
+
+    /// `pos` must be where the entity was inserted (or last relocated) to.
+    fn remove(&mut self, entity: Entity, pos: Vec3);
+
+    /// Move something we already hold. Grids only shuffle it between cells when its cell changes.
+    fn relocate(&mut self, entity: Entity, from: Vec3, to: Vec3)
+    {
+        self.remove(entity, from);
+        self.insert(entity, to);
+    }
+
+    /// Whether moving something from `from` to `to` leaves it where it is in
+    /// the index (the same cell, say), so `move_in_place` will do.
+    fn stays_put(&self, _entity: Entity, _from: Vec3, _to: Vec3) -> bool
+    {
+        false
+    }
+
+    /// Move something that `stays_put`, just updating where it is.
+    fn move_in_place(&mut self, entity: Entity, from: Vec3, to: Vec3)
+    {
+        self.relocate(entity, from, to);
+    }
+
+    /// Called after a batch of inserts/removes/relocations, before querying again.
+    fn refresh(&mut self) {}
+
+    /// Throw away whatever we had and index exactly these.
+    fn rebuild(&mut self, items: &[(Entity, Vec3)])
+    {
+        self.clear();
+        for (entity, pos) in items.iter() {
+            self.insert(*entity, *pos);
+        }
+    }
+
+    /// Everything within `radius` of `pos`, nearest first.
+    fn within_radius(&self, pos: Vec3, radius: f32, exclude: Option<Entity>) -> Vec<Neighbour>;
+
+    /// The `k` things nearest to `pos`, nearest first.
+    fn k_nearest(&self, pos: Vec3, k: usize, exclude: Option<Entity>) -> Vec<Neighbour>;
+}
+
+// Which index to build, picked when adding JayObserve.
+#[derive(Clone, Copy, Debug)]
+pub enum IndexBackend {
+    UniformGrid {
+        origin: Vec3,
+        width: usize,
+        height: usize,
+        depth: usize,
+        cell_size: f32,
+    },
+    SparseGrid {
+        cell_size: f32,
+    },
+    KdTree,
+}
+
+impl IndexBackend {
+    pub fn sparse_grid_for(bounds: &Bounds) -> IndexBackend {
+        IndexBackend::SparseGrid {
+            cell_size: bounds.cell_size,
+        }
+    }
+
+    pub fn uniform_grid_for(bounds: &Bounds) -> IndexBackend {
+        IndexBackend::UniformGrid {
+            origin: Vec3::new(bounds.x_min, bounds.y_min, bounds.z_min),
+            width: bounds.cells_x,
+            height: bounds.cells_y,
+            depth: bounds.cells_z,
+            cell_size: bounds.cell_size,
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn SpatialIndex> {
+        match *self {
+            IndexBackend::UniformGrid { origin, width, height, depth, cell_size } =>
+                Box::new(UniformGrid::new(origin, width, height, depth, cell_size)),
+            IndexBackend::SparseGrid { cell_size } =>
+                Box::new(SparseGrid::new(cell_size)),
+            IndexBackend::KdTree =>
+                Box::new(KdTree::default()),
+        }
+    }
+}
+
+fn neighbour_of(pos: Vec3, entity: Entity, other_pos: Vec3) -> Neighbour
+{
+    let offset = other_pos - pos;
+    Neighbour {
+        entity,
+        offset,
+        distance: offset.length(),
+        velocity: None,
+        seen_on: 0,
+    }
+}
+
+fn remove_from(bucket: &mut Vec<(Entity, Vec3)>, entity: Entity)
+{
+    if let Some(at) = bucket.iter().position(|(e, _)| *e == entity) {
+        bucket.swap_remove(at);
+    }
+}
+
+fn move_within(bucket: &mut Vec<(Entity, Vec3)>, entity: Entity, to: Vec3)
+{
+    if let Some(item) = bucket.iter_mut().find(|(e, _)| *e == entity) {
+        item.1 = to;
+    }
+}
+
+fn sort_by_distance(found: &mut Vec<Neighbour>)
+{
+    found.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Ordering::Equal));
+}
+
+/*
+ * Uniform grid
+ */
+
+// A fixed-size grid of cells starting at `origin`, hashed on x/y/z.
+// Cells are laid out x first, then z, then y (so each altitude layer is one
+// horizontal slab of width * depth cells). Anything that strays outside the
+// grid is kept to one side in a sparse grid of its own, rather than being
+// piled into whichever edge cell is closest.
+pub struct UniformGrid {
+    stuff: Vec<Vec<(Entity, Vec3)>>,
+    outside: SparseGrid,
+    origin: Vec3,
+    cell_size: f32,
+    width: usize,
+    height: usize,
+    depth: usize,
+}
+
+impl UniformGrid {
+    pub fn new(origin: Vec3, width: usize, height: usize, depth: usize, cell_size: f32) -> UniformGrid {
+        // Always keep at least one cell along each axis so the hash never has nowhere to go.
+        let width = width.max(1);
+        let height = height.max(1);
+        let depth = depth.max(1);
+
+        let mut stuff = Vec::new();
+        let size = width * height * depth;
+        for _ in 0..size {
+            stuff.push(Vec::new());
+        }
+        UniformGrid {
+            stuff,
+            outside: SparseGrid::new(cell_size),
+            origin,
+            cell_size,
+            width,
+            height,
+            depth,
+        }
+    }
+
+    // Which cell a position falls in, even if that's off the grid.
+    fn coords(&self, pos: Vec3) -> IVec3
+    {
+        ((pos - self.origin) / self.cell_size).floor().as_ivec3()
+    }
+
+    // The cell a position falls in, if it's on the grid at all.
+    fn hash(&self, pos: Vec3) -> Option<usize>
+    {
+        if self.cell_size <= 0.
+        { return Some(0); }
+
+        let c = self.coords(pos);
+        if !coord_valid(c.x, self.width)
+            || !coord_valid(c.y, self.height)
+            || !coord_valid(c.z, self.depth)
+        { return None; }
+
+        Some(cell_index(c.x as usize, c.y as usize, c.z as usize, self.width, self.depth))
+    }
+
+    // Every cell on the grid up to `reach` cells away from `centre` along each
+    // axis (so reach 1 is the 26-cell neighbourhood). Neighbours are found by
+    // grid coordinates so we never wrap from the end of one row onto the next.
+    fn collect_cells(&self, centre: IVec3, reach: usize) -> Vec<usize>
+    {
+        let mut all_cells = Vec::new();
+
+        let r = reach as i32;
+        let (x_lo, x_hi) = clamp_span(centre.x, r, self.width);
+        let (y_lo, y_hi) = clamp_span(centre.y, r, self.height);
+        let (z_lo, z_hi) = clamp_span(centre.z, r, self.depth);
+
+        for y in y_lo..y_hi {
+            for z in z_lo..z_hi {
+                for x in x_lo..x_hi {
+                    all_cells.push(cell_index(x, y, z, self.width, self.depth));
+                }
+            }
+        }
+
+        all_cells
+    }
+
+    // The most cells we would ever need to step out from `centre` to cover the whole grid.
+    fn max_reach(&self, centre: IVec3) -> usize
+    {
+        let far = |c: i32, size: usize| c.abs().max((size as i32 - 1 - c).abs()) as usize;
+        far(centre.x, self.width)
+            .max(far(centre.y, self.height))
+            .max(far(centre.z, self.depth))
+            .max(1)
+    }
+
+    // As max_reach, but out to any strays as well.
+    fn reach_limit(&self, pos: Vec3) -> usize
+    {
+        self.max_reach(self.coords(pos)).max(self.outside.max_reach(pos) as usize)
+    }
+
+    fn gather(&self, pos: Vec3, reach: usize, exclude: Option<Entity>, found: &mut Vec<Neighbour>)
+    {
+        for near_cell in self.collect_cells(self.coords(pos), reach).iter()
+        {
+            for (entity, other_pos) in self.stuff[*near_cell].iter() {
+                if Some(*entity) == exclude { continue; }
+                found.push(neighbour_of(pos, *entity, *other_pos));
+            }
+        }
+        self.outside.gather(pos, reach as i32, exclude, found);
+    }
+}
+
+impl SpatialIndex for UniformGrid {
+    fn clear(&mut self)
+    {
+        for thing in self.stuff.iter_mut() {
+            thing.clear();
+        }
+        self.outside.clear();
+    }
+
+    fn insert(&mut self, entity: Entity, pos: Vec3)
+    {
+        match self.hash(pos) {
+            Some(cell) => self.stuff[cell].push((entity, pos)),
+            None => self.outside.insert(entity, pos),
+        }
+    }
+
+    fn remove(&mut self, entity: Entity, pos: Vec3)
+    {
+        match self.hash(pos) {
+            Some(cell) => remove_from(&mut self.stuff[cell], entity),
+            None => self.outside.remove(entity, pos),
+        }
+    }
+
+    fn relocate(&mut self, entity: Entity, from: Vec3, to: Vec3)
+    {
+        let cell = self.hash(to);
+        if self.hash(from) != cell {
+            self.remove(entity, from);
+            self.insert(entity, to);
+            return;
+        }
+
+        match cell {
+            Some(cell) => move_within(&mut self.stuff[cell], entity, to),
+            None => self.outside.relocate(entity, from, to),
+        }
+    }
+
+    fn stays_put(&self, entity: Entity, from: Vec3, to: Vec3) -> bool
+    {
+        match (self.hash(from), self.hash(to)) {
+            (Some(from_cell), Some(to_cell)) => from_cell == to_cell,
+            (None, None) => self.outside.stays_put(entity, from, to),
+            _ => false,
+        }
+    }
+
+    fn within_radius(&self, pos: Vec3, radius: f32, exclude: Option<Entity>) -> Vec<Neighbour>
+    {
+        let mut found = Vec::new();
+        if self.cell_size <= 0. { return found; }
+
+        let reach = ((radius / self.cell_size).ceil() as usize).clamp(1, self.reach_limit(pos));
+        self.gather(pos, reach, exclude, &mut found);
+        found.retain(|n| n.distance <= radius);
+        sort_by_distance(&mut found);
+        found
+    }
+
+    fn k_nearest(&self, pos: Vec3, k: usize, exclude: Option<Entity>) -> Vec<Neighbour>
+    {
+        let mut found = Vec::new();
+        if k == 0 || self.cell_size <= 0. { return found; }
+
+        // Step outwards until the k-th nearest is guaranteed to be inside the
+        // block of cells we've looked at (or we've looked at everything).
+        let max_reach = self.reach_limit(pos);
+        let mut reach = 1;
+        loop {
+            found.clear();
+            self.gather(pos, reach, exclude, &mut found);
+            sort_by_distance(&mut found);
+
+            let covered = reach as f32 * self.cell_size;
+            if reach >= max_reach
+                || (found.len() >= k && found[k - 1].distance <= covered)
+            { break; }
+
+            reach += 1;
+        }
+
+        found.truncate(k);
+        found
+    }
+}
+
+fn coord_valid(coord: i32, size: usize) -> bool
+{
+    coord >= 0 && (coord as usize) < size
+}
+
+// The on-grid part of centre - reach ..= centre + reach, as a half-open range.
+fn clamp_span(centre: i32, reach: i32, size: usize) -> (usize, usize)
+{
+    let lo = (centre - reach).max(0) as usize;
+    let hi = ((centre + reach + 1).max(0) as usize).min(size);
+    (lo, hi.max(lo))
+}
+
+fn cell_index(x: usize, y: usize, z: usize, width: usize, depth: usize) -> usize
+{
+    x + z * width + y * width * depth
+}
+
+/*
+ * Sparse grid
+ */
+
+// How many stored cells there are at each coordinate along one axis, so we
+// always know how far they spread without looking through them all.
+#[derive(Default)]
+struct Extent(BTreeMap<i32, usize>);
+
+impl Extent {
+    fn add(&mut self, at: i32)
+    {
+        *self.0.entry(at).or_insert(0) += 1;
+    }
+
+    fn take(&mut self, at: i32)
+    {
+        if let Some(count) = self.0.get_mut(&at) {
+            *count -= 1;
+            if *count == 0 {
+                self.0.remove(&at);
+            }
+        }
+    }
+
+    // Furthest any stored coordinate is from `from`.
+    fn reach(&self, from: i32) -> i32
+    {
+        match (self.0.keys().next(), self.0.keys().next_back()) {
+            (Some(lo), Some(hi)) => (from - lo).abs().max((hi - from).abs()),
+            _ => 0,
+        }
+    }
+}
+
+fn occupy(extents: &mut [Extent; 3], key: IVec3)
+{
+    extents[0].add(key.x);
+    extents[1].add(key.y);
+    extents[2].add(key.z);
+}
+
+fn vacate(extents: &mut [Extent; 3], key: IVec3)
+{
+    extents[0].take(key.x);
+    extents[1].take(key.y);
+    extents[2].take(key.z);
+}
+
+// A grid with no edges: only cells that actually hold something are stored,
+// keyed by their signed cell coordinates.
+pub struct SparseGrid {
+    stuff: HashMap<IVec3, Vec<(Entity, Vec3)>>,
+    extents: [Extent; 3],
+    cell_size: f32,
+}
+
+impl SparseGrid {
+    pub fn new(cell_size: f32) -> SparseGrid {
+        SparseGrid {
+            stuff: HashMap::new(),
+            extents: Default::default(),
+            cell_size,
+        }
+    }
+
+    fn key(&self, pos: Vec3) -> IVec3
+    {
+        (pos / self.cell_size).floor().as_ivec3()
+    }
+
+    fn gather(&self, pos: Vec3, reach: i32, exclude: Option<Entity>, found: &mut Vec<Neighbour>)
+    {
+        let centre = self.key(pos);
+        let span = (2 * reach as i64 + 1).pow(3);
+
+        let mut visit = |cell: &Vec<(Entity, Vec3)>| {
+            for (entity, other_pos) in cell.iter() {
+                if Some(*entity) == exclude { continue; }
+                found.push(neighbour_of(pos, *entity, *other_pos));
+            }
+        };
+
+        if span > self.stuff.len() as i64 {
+            // Fewer occupied cells than we'd visit: just check each one.
+            for (key, cell) in self.stuff.iter() {
+                if (*key - centre).abs().max_element() <= reach {
+                    visit(cell);
+                }
+            }
+        } else {
+            for dy in -reach..=reach {
+                for dz in -reach..=reach {
+                    for dx in -reach..=reach {
+                        if let Some(cell) = self.stuff.get(&(centre + IVec3::new(dx, dy, dz))) {
+                            visit(cell);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // How many cells out from `pos` we'd need to step to reach every occupied cell.
+    fn max_reach(&self, pos: Vec3) -> i32
+    {
+        let centre = self.key(pos);
+        self.extents[0].reach(centre.x)
+            .max(self.extents[1].reach(centre.y))
+            .max(self.extents[2].reach(centre.z))
+            .max(1)
+    }
+}
+
+impl SpatialIndex for SparseGrid {
+    fn clear(&mut self)
+    {
+        // Keep the allocations around for cells we're likely to reuse,
+        // but forget ones that have gone quiet.
+        let extents = &mut self.extents;
+        self.stuff.retain(|key, cell| {
+            if cell.is_empty() {
+                vacate(extents, *key);
+            }
+            !cell.is_empty()
+        });
+        for cell in self.stuff.values_mut() {
+            cell.clear();
+        }
+    }
+
+    fn insert(&mut self, entity: Entity, pos: Vec3)
+    {
+        let key = self.key(pos);
+        if !self.stuff.contains_key(&key) {
+            occupy(&mut self.extents, key);
+        }
+        self.stuff.entry(key).or_insert_with(Vec::new).push((entity, pos));
+    }
+
+    fn remove(&mut self, entity: Entity, pos: Vec3)
+    {
+        let key = self.key(pos);
+        if let Some(cell) = self.stuff.get_mut(&key) {
+            remove_from(cell, entity);
+            if cell.is_empty() {
+                self.stuff.remove(&key);
+                vacate(&mut self.extents, key);
+            }
+        }
+    }
+
+    fn relocate(&mut self, entity: Entity, from: Vec3, to: Vec3)
+    {
+        let key = self.key(to);
+        if self.key(from) != key {
+            self.remove(entity, from);
+            self.insert(entity, to);
+            return;
+        }
+
+        if let Some(cell) = self.stuff.get_mut(&key) {
+            move_within(cell, entity, to);
+        }
+    }
+
+    fn stays_put(&self, _entity: Entity, from: Vec3, to: Vec3) -> bool
+    {
+        self.key(from) == self.key(to)
+    }
+
+    fn within_radius(&self, pos: Vec3, radius: f32, exclude: Option<Entity>) -> Vec<Neighbour>
+    {
+        let mut found = Vec::new();
+        if self.cell_size <= 0. { return found; }
+
+        let reach = ((radius / self.cell_size).ceil() as i32).clamp(1, self.max_reach(pos));
+        self.gather(pos, reach, exclude, &mut found);
+        found.retain(|n| n.distance <= radius);
+        sort_by_distance(&mut found);
+        found
+    }
+
+    fn k_nearest(&self, pos: Vec3, k: usize, exclude: Option<Entity>) -> Vec<Neighbour>
+    {
+        let mut found = Vec::new();
+        if k == 0 || self.cell_size <= 0. { return found; }
+
+        let max_reach = self.max_reach(pos);
+        let mut reach = 1;
+        loop {
+            found.clear();
+            self.gather(pos, reach, exclude, &mut found);
+            sort_by_distance(&mut found);
+
+            let covered = reach as f32 * self.cell_size;
+            if reach >= max_reach
+                || (found.len() >= k && found[k - 1].distance <= covered)
+            { break; }
+
+            reach += 1;
+        }
+
+        found.truncate(k);
+        found
+    }
+}
+
+/*
+ * k-d tree
+ */
+
+struct KdNode {
+    entity: Entity,
+    pos: Vec3,
+    axis: usize,
+    // Where this node splits its children along `axis`. It starts as the
+    // node's own position but stays put when the node moves.
+    split: f32,
+    // The box this node's branch covers, from the splits above it.
+    lo: Vec3,
+    hi: Vec3,
+    left: Option<usize>,
+    right: Option<usize>,
+    removed: bool,
+}
+
+impl KdNode {
+    fn covers(&self, pos: Vec3) -> bool
+    {
+        pos.cmpge(self.lo).all() && pos.cmplt(self.hi).all()
+    }
+
+    // The boxes of the branches either side of our split.
+    fn left_box(&self) -> (Vec3, Vec3)
+    {
+        let mut hi = self.hi;
+        hi[self.axis] = self.split;
+        (self.lo, hi)
+    }
+
+    fn right_box(&self) -> (Vec3, Vec3)
+    {
+        let mut lo = self.lo;
+        lo[self.axis] = self.split;
+        (lo, self.hi)
+    }
+}
+
+// A 3D k-d tree. Rebuilding gives a balanced tree; inserting in between
+// just hangs new leaves off the existing one. Something that moves stays in
+// its node as long as it's still inside that node's box; otherwise its old
+// node is dropped and it's hung off the tree afresh. The tree's only rebuilt
+// once dropped nodes outnumber live ones, so only what moved is touched.
+// Its leaves' boxes are small, though, so a busy flock leaves them often;
+// the grids are cheaper for that.
+#[derive(Default)]
+pub struct KdTree {
+    nodes: Vec<KdNode>,
+    root: Option<usize>,
+    slots: HashMap<Entity, usize>,
+    removed: usize,
+}
+
+impl KdTree {
+    fn build(&mut self, items: &mut [(Entity, Vec3)], depth: usize, (lo, hi): (Vec3, Vec3)) -> Option<usize>
+    {
+        if items.is_empty() { return None; }
+
+        let axis = depth % 3;
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |a, b| {
+            a.1[axis].partial_cmp(&b.1[axis]).unwrap_or(Ordering::Equal)
+        });
+
+        let (entity, pos) = items[mid];
+        let index = self.nodes.len();
+        self.nodes.push(KdNode {
+            entity,
+            pos,
+            axis,
+            split: pos[axis],
+            lo,
+            hi,
+            left: None,
+            right: None,
+            removed: false,
+        });
+        self.slots.insert(entity, index);
+
+        let (left_box, right_box) = (self.nodes[index].left_box(), self.nodes[index].right_box());
+        let (lower, upper) = items.split_at_mut(mid);
+        let left = self.build(lower, depth + 1, left_box);
+        let right = self.build(&mut upper[1..], depth + 1, right_box);
+        self.nodes[index].left = left;
+        self.nodes[index].right = right;
+
+        Some(index)
+    }
+
+    fn drop_node(&mut self, entity: Entity)
+    {
+        if let Some(index) = self.slots.remove(&entity) {
+            self.nodes[index].removed = true;
+            self.removed += 1;
+        }
+    }
+
+    fn radius_search(&self, node: Option<usize>, pos: Vec3, radius: f32, exclude: Option<Entity>, found: &mut Vec<Neighbour>)
+    {
+        let node = match node {
+            Some(index) => &self.nodes[index],
+            None => return,
+        };
+
+        if !node.removed && Some(node.entity) != exclude {
+            let neighbour = neighbour_of(pos, node.entity, node.pos);
+            if neighbour.distance <= radius {
+                found.push(neighbour);
+            }
+        }
+
+        let diff = pos[node.axis] - node.split;
+        let (near, far) = if diff < 0. { (node.left, node.right) } else { (node.right, node.left) };
+
+        self.radius_search(near, pos, radius, exclude, found);
+        if diff.abs() <= radius {
+            self.radius_search(far, pos, radius, exclude, found);
+        }
+    }
+
+    // `best` is kept sorted, nearest first, and never longer than k.
+    fn nearest_search(&self, node: Option<usize>, pos: Vec3, k: usize, exclude: Option<Entity>, best: &mut Vec<Neighbour>)
+    {
+        let node = match node {
+            Some(index) => &self.nodes[index],
+            None => return,
+        };
+
+        if !node.removed && Some(node.entity) != exclude {
+            let neighbour = neighbour_of(pos, node.entity, node.pos);
+            if best.len() < k || neighbour.distance < best[best.len() - 1].distance {
+                let at = best.partition_point(|n| n.distance <= neighbour.distance);
+                best.insert(at, neighbour);
+                best.truncate(k);
+            }
+        }
+
+        let diff = pos[node.axis] - node.split;
+        let (near, far) = if diff < 0. { (node.left, node.right) } else { (node.right, node.left) };
+
+        self.nearest_search(near, pos, k, exclude, best);
+        if best.len() < k || diff.abs() < best[best.len() - 1].distance {
+            self.nearest_search(far, pos, k, exclude, best);
+        }
+    }
+}
+
+impl SpatialIndex for KdTree {
+    fn clear(&mut self)
+    {
+        self.nodes.clear();
+        self.slots.clear();
+        self.root = None;
+        self.removed = 0;
+    }
+
+    fn insert(&mut self, entity: Entity, pos: Vec3)
+    {
+        // Anything we already hold only gets the one node: forget the old one.
+        self.drop_node(entity);
+
+        let index = self.nodes.len();
+        self.slots.insert(entity, index);
+        let node = |axis: usize, (lo, hi): (Vec3, Vec3)| KdNode {
+            entity,
+            pos,
+            axis,
+            split: pos[axis],
+            lo,
+            hi,
+            left: None,
+            right: None,
+            removed: false,
+        };
+
+        let mut parent = match self.root {
+            Some(root) => root,
+            None => {
+                self.nodes.push(node(0, (Vec3::splat(f32::NEG_INFINITY), Vec3::splat(f32::INFINITY))));
+                self.root = Some(index);
+                return;
+            }
+        };
+
+        loop {
+            let axis = self.nodes[parent].axis;
+            let go_left = pos[axis] < self.nodes[parent].split;
+            let next = if go_left { self.nodes[parent].left } else { self.nodes[parent].right };
+
+            match next {
+                Some(child) => parent = child,
+                None => {
+                    let area = if go_left { self.nodes[parent].left_box() } else { self.nodes[parent].right_box() };
+                    self.nodes.push(node((axis + 1) % 3, area));
+                    if go_left {
+                        self.nodes[parent].left = Some(index);
+                    } else {
+                        self.nodes[parent].right = Some(index);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, entity: Entity, _pos: Vec3)
+    {
+        self.drop_node(entity);
+    }
+
+    fn relocate(&mut self, entity: Entity, from: Vec3, to: Vec3)
+    {
+        if self.stays_put(entity, from, to) {
+            self.move_in_place(entity, from, to);
+        } else {
+            self.insert(entity, to);
+        }
+    }
+
+    fn stays_put(&self, entity: Entity, _from: Vec3, to: Vec3) -> bool
+    {
+        self.slots.get(&entity).map_or(false, |index| self.nodes[*index].covers(to))
+    }
+
+    fn move_in_place(&mut self, entity: Entity, _from: Vec3, to: Vec3)
+    {
+        if let Some(index) = self.slots.get(&entity) {
+            self.nodes[*index].pos = to;
+        }
+    }
+
+    fn refresh(&mut self)
+    {
+        // Dropped nodes still cost us on every search, so clear them out
+        // once there are more of them than there are live ones.
+        if self.removed <= self.slots.len() { return; }
+
+        let items: Vec<(Entity, Vec3)> = self.nodes.iter()
+            .filter(|node| !node.removed)
+            .map(|node| (node.entity, node.pos))
+            .collect();
+        self.rebuild(&items);
+    }
+
+    fn rebuild(&mut self, items: &[(Entity, Vec3)])
+    {
+        self.clear();
+        let mut items = items.to_vec();
+        let everywhere = (Vec3::splat(f32::NEG_INFINITY), Vec3::splat(f32::INFINITY));
+        self.root = self.build(&mut items, 0, everywhere);
+    }
+
+    fn within_radius(&self, pos: Vec3, radius: f32, exclude: Option<Entity>) -> Vec<Neighbour>
+    {
+        let mut found = Vec::new();
+        self.radius_search(self.root, pos, radius, exclude, &mut found);
+        sort_by_distance(&mut found);
+        found
+    }
+
+    fn k_nearest(&self, pos: Vec3, k: usize, exclude: Option<Entity>) -> Vec<Neighbour>
+    {
+        let mut best = Vec::new();
+        if k == 0 { return best; }
+
+        self.nearest_search(self.root, pos, k, exclude, &mut best);
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn grid_4() -> UniformGrid {
+        UniformGrid::new(Vec3::ZERO, 4, 4, 4, 10.0)
+    }
+
+    #[test]
+    fn hash_separates_altitudes() {
+        let grid = grid_4();
+        assert_ne!(grid.hash(Vec3::new(5.0, 5.0, 5.0)), grid.hash(Vec3::new(5.0, 35.0, 5.0)));
+    }
+
+    #[test]
+    fn hash_separates_depth() {
+        let grid = grid_4();
+        assert_ne!(grid.hash(Vec3::new(5.0, 5.0, 5.0)), grid.hash(Vec3::new(5.0, 5.0, 35.0)));
+    }
+
+    #[test]
+    fn hash_is_none_off_the_grid() {
+        let grid = grid_4();
+        assert_eq!(grid.hash(Vec3::new(-5.0, 5.0, 5.0)), None);
+        assert_eq!(grid.hash(Vec3::new(5.0, 45.0, 5.0)), None);
+        assert!(grid.hash(Vec3::new(35.0, 35.0, 35.0)).is_some());
+    }
+
+    #[test]
+    fn hash_respects_origin() {
+        let grid = UniformGrid::new(Vec3::new(-40.0, 0.0, -40.0), 4, 4, 4, 10.0);
+        assert_eq!(grid.hash(Vec3::new(-35.0, 5.0, -35.0)), Some(0));
+    }
+
+    #[test]
+    fn collect_cells_interior_is_27() {
+        let grid = UniformGrid::new(Vec3::ZERO, 3, 3, 3, 1.0);
+        let cells = grid.collect_cells(IVec3::new(1, 1, 1), 1);
+        assert_eq!(cells.len(), 27);
+    }
+
+    #[test]
+    fn collect_cells_does_not_wrap_rows() {
+        let grid = UniformGrid::new(Vec3::ZERO, 4, 1, 4, 1.0);
+        // Last cell of the first row: its "right" neighbour by index arithmetic
+        // would be the first cell of the second row.
+        let cells = grid.collect_cells(IVec3::new(3, 0, 0), 1);
+        assert_eq!(cells.len(), 4);
+        assert!(!cells.contains(&cell_index(0, 0, 1, 4, 4)));
+    }
+
+    #[test]
+    fn strays_do_not_land_in_edge_cells() {
+        let mut grid = grid_4();
+        grid.insert(Entity::from_raw(0), Vec3::new(-500.0, 5.0, 5.0));
+        assert!(grid.stuff.iter().all(|cell| cell.is_empty()));
+
+        // ...but can still be seen by (and see) anyone close enough.
+        let found = grid.within_radius(Vec3::new(-495.0, 5.0, 5.0), 10.0, None);
+        assert_eq!(found.len(), 1);
+        assert!(grid.within_radius(Vec3::new(5.0, 5.0, 5.0), 10.0, None).is_empty());
+        assert_eq!(grid.k_nearest(Vec3::new(5.0, 5.0, 5.0), 1, None).len(), 1);
+    }
+
+    #[test]
+    fn sparse_reach_follows_what_is_stored() {
+        let mut grid = SparseGrid::new(10.0);
+        grid.insert(Entity::from_raw(0), Vec3::new(5.0, 5.0, 5.0));
+        grid.insert(Entity::from_raw(1), Vec3::new(95.0, 5.0, 5.0));
+        assert_eq!(grid.max_reach(Vec3::new(5.0, 5.0, 5.0)), 9);
+
+        grid.remove(Entity::from_raw(1), Vec3::new(95.0, 5.0, 5.0));
+        grid.insert(Entity::from_raw(1), Vec3::new(5.0, -25.0, 5.0));
+        assert_eq!(grid.max_reach(Vec3::new(5.0, 5.0, 5.0)), 3);
+
+        // Cells are kept for a clear in case they're reused, then dropped.
+        grid.clear();
+        assert_eq!(grid.max_reach(Vec3::new(5.0, 5.0, 5.0)), 3);
+        grid.clear();
+        assert_eq!(grid.max_reach(Vec3::new(5.0, 5.0, 5.0)), 1);
+    }
+
+    fn items_from(points: &[Vec3]) -> Vec<(Entity, Vec3)> {
+        points.iter()
+            .enumerate()
+            .map(|(i, pos)| (Entity::from_raw(i as u32), *pos))
+            .collect()
+    }
+
+    fn all_backends() -> Vec<Box<dyn SpatialIndex>> {
+        vec![
+            IndexBackend::UniformGrid { origin: Vec3::ZERO, width: 10, height: 10, depth: 10, cell_size: 10.0 }.build(),
+            IndexBackend::SparseGrid { cell_size: 10.0 }.build(),
+            IndexBackend::KdTree.build(),
+        ]
+    }
+
+    #[test]
+    fn within_radius_is_sorted_and_bounded() {
+        let items = items_from(&[
+            Vec3::new(50.0, 50.0, 50.0),
+            Vec3::new(58.0, 50.0, 50.0),
+            Vec3::new(52.0, 50.0, 50.0),
+            Vec3::new(50.0, 50.0, 75.0),
+        ]);
+        for mut index in all_backends() {
+            index.rebuild(&items);
+            let found = index.within_radius(Vec3::new(50.0, 50.0, 50.0), 10.0, Some(Entity::from_raw(0)));
+            assert_eq!(found.len(), 2);
+            assert_eq!(found[0].entity, Entity::from_raw(2));
+            assert_eq!(found[1].entity, Entity::from_raw(1));
+            assert_eq!(found[1].offset, Vec3::new(8.0, 0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn within_radius_reaches_past_one_cell() {
+        let items = items_from(&[
+            Vec3::new(5.0, 5.0, 5.0),
+            Vec3::new(30.0, 5.0, 5.0),
+        ]);
+        for mut index in all_backends() {
+            index.rebuild(&items);
+            let found = index.within_radius(Vec3::new(5.0, 5.0, 5.0), 26.0, Some(Entity::from_raw(0)));
+            assert_eq!(found.len(), 1);
+        }
+    }
+
+    #[test]
+    fn k_nearest_steps_outwards() {
+        let items = items_from(&[
+            Vec3::new(5.0, 5.0, 5.0),
+            Vec3::new(95.0, 5.0, 5.0),
+            Vec3::new(5.0, 99.0, 5.0),
+            Vec3::new(45.0, 5.0, 5.0),
+        ]);
+        for mut index in all_backends() {
+            index.rebuild(&items);
+            let found = index.k_nearest(Vec3::new(5.0, 5.0, 5.0), 2, Some(Entity::from_raw(0)));
+            assert_eq!(found.len(), 2);
+            assert_eq!(found[0].entity, Entity::from_raw(3));
+            assert_eq!(found[1].entity, Entity::from_raw(1));
+        }
+    }
+
+    #[test]
+    fn inserting_again_replaces() {
+        for mut index in all_backends() {
+            index.insert(Entity::from_raw(0), Vec3::new(50.0, 50.0, 50.0));
+            index.insert(Entity::from_raw(1), Vec3::new(52.0, 50.0, 50.0));
+            index.remove(Entity::from_raw(1), Vec3::new(52.0, 50.0, 50.0));
+            index.insert(Entity::from_raw(1), Vec3::new(52.0, 50.0, 50.0));
+            index.refresh();
+            assert_eq!(index.within_radius(Vec3::new(50.0, 50.0, 50.0), 10.0, None).len(), 2);
+        }
+
+        let mut tree = KdTree::default();
+        tree.insert(Entity::from_raw(0), Vec3::new(50.0, 50.0, 50.0));
+        tree.insert(Entity::from_raw(0), Vec3::new(51.0, 50.0, 50.0));
+        let found = tree.within_radius(Vec3::new(50.0, 50.0, 50.0), 10.0, None);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].offset, Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn kd_small_moves_stay_in_their_nodes() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let points: Vec<Vec3> = (0..200)
+            .map(|_| Vec3::new(rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0)))
+            .collect();
+        let mut tree = KdTree::default();
+        tree.rebuild(&items_from(&points));
+
+        for (i, pos) in points.iter().enumerate() {
+            tree.relocate(Entity::from_raw(i as u32), *pos, *pos + Vec3::splat(0.01));
+        }
+        tree.refresh();
+
+        // Most stayed where they were rather than being hung off the tree again.
+        assert!(tree.removed < points.len() / 4);
+        assert_eq!(tree.k_nearest(points[0], 1, None)[0].entity, Entity::from_raw(0));
+    }
+
+    #[test]
+    fn backends_agree() {
+        let mut rng = StdRng::seed_from_u64(4);
+        // Some of these stray outside the uniform grid.
+        let points: Vec<Vec3> = (0..500)
+            .map(|_| Vec3::new(rng.gen_range(-20.0..120.0), rng.gen_range(-20.0..120.0), rng.gen_range(-20.0..120.0)))
+            .collect();
+        let items = items_from(&points);
+
+        let mut backends = all_backends();
+        for index in backends.iter_mut() {
+            index.rebuild(&items);
+        }
+
+        // Inserting one by one should find the same things as rebuilding.
+        let mut inserted = IndexBackend::KdTree.build();
+        for (entity, pos) in items.iter() {
+            inserted.insert(*entity, *pos);
+        }
+        backends.push(inserted);
+
+        for _ in 0..50 {
+            let pos = Vec3::new(rng.gen_range(-20.0..120.0), rng.gen_range(-20.0..120.0), rng.gen_range(-20.0..120.0));
+
+            let expected: Vec<Entity> = backends[0].within_radius(pos, 15.0, None).iter().map(|n| n.entity).collect();
+            let expected_k: Vec<f32> = backends[0].k_nearest(pos, 7, None).iter().map(|n| n.distance).collect();
+            for index in backends.iter().skip(1) {
+                let mut found: Vec<Entity> = index.within_radius(pos, 15.0, None).iter().map(|n| n.entity).collect();
+                let mut sorted_expected = expected.clone();
+                found.sort();
+                sorted_expected.sort();
+                assert_eq!(found, sorted_expected);
+
+                let found_k: Vec<f32> = index.k_nearest(pos, 7, None).iter().map(|n| n.distance).collect();
+                assert_eq!(found_k, expected_k);
+            }
+        }
+    }
+
+    #[test]
+    fn relocating_matches_rebuilding() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let mut points: Vec<Vec3> = (0..300)
+            .map(|_| Vec3::new(rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0)))
+            .collect();
+
+        let mut backends = all_backends();
+        for index in backends.iter_mut() {
+            index.rebuild(&items_from(&points));
+        }
+
+        // Nudge some a little (mostly staying in their cells), fling some far, and drop one.
+        for (i, pos) in points.iter_mut().enumerate() {
+            let to = if i % 10 == 0 {
+                Vec3::new(rng.gen_range(-20.0..120.0), rng.gen_range(-20.0..120.0), rng.gen_range(-20.0..120.0))
+            } else {
+                *pos + Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0))
+            };
+            for index in backends.iter_mut() {
+                index.relocate(Entity::from_raw(i as u32), *pos, to);
+            }
+            *pos = to;
+        }
+        for index in backends.iter_mut() {
+            index.remove(Entity::from_raw(7), points[7]);
+            index.refresh();
+        }
+
+        let mut fresh = IndexBackend::KdTree.build();
+        let mut items = items_from(&points);
+        items.remove(7);
+        fresh.rebuild(&items);
+
+        for _ in 0..50 {
+            let pos = Vec3::new(rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0));
+            let expected: Vec<f32> = fresh.k_nearest(pos, 5, None).iter().map(|n| n.distance).collect();
+            let expected_r: Vec<f32> = fresh.within_radius(pos, 12.0, None).iter().map(|n| n.distance).collect();
+            for index in backends.iter() {
+                let found: Vec<f32> = index.k_nearest(pos, 5, None).iter().map(|n| n.distance).collect();
+                assert_eq!(found, expected);
+                let found_r: Vec<f32> = index.within_radius(pos, 12.0, None).iter().map(|n| n.distance).collect();
+                assert_eq!(found_r, expected_r);
+            }
+        }
+    }
+}
@@ -0,0 +1,617 @@
+use bevy::{
+    prelude::*,
+};
+use rand::prelude::*;
+
+use crate::observe;
+use observe::*;
+use crate::velocitate;
+use velocitate::*;
+use crate::bounds;
+use bounds::*;
+use crate::boids;
+use boids::*;
+use crate::avoidance;
+use avoidance::*;
+use crate::altitude;
+use altitude::*;
+
+// Our own plugin. Each behaviour is a component that works out its own
+// steering factor (like the boids rules do); insert whichever ones an
+// entity should have and they're combined, along with the boids rules,
+// into its Steering as its SteeringMode says.
+pub struct JaySteering;
+
+impl Plugin for JaySteering {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<SteeringPriorities>()
+            .add_system_set(
+                SystemSet::new()
+                    .label(SteeringSystem::Behaviours)
+                    .after(ObserveSystem::UpdateObserved)
+                    .with_system(seek_system)
+                    .with_system(flee_system)
+                    .with_system(arrive_system)
+                    .with_system(pursue_system)
+                    .with_system(evade_system)
+                    .with_system(wander_system)
+                    .with_system(path_follow_system)
+                    .with_system(leader_follow_system)
+                    .with_system(obstacle_avoid_system)
+            )
+            .add_system(steering_insert_system.before(SteeringSystem::Combine))
+            .add_system(steering_sum_system
+                .label(SteeringSystem::Combine)
+                .after(SteeringSystem::Behaviours)
+                .after(BoidsSystem::Rules)
+                .before(VelocitateSystem::Update));
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SteeringSystem {
+    Behaviours,
+    Combine,
+}
+
+// The combined steering force from all of an entity's behaviours, boids rules included.
+#[derive(Component, Debug, Default)]
+pub struct Steering {
+    pub force: Vec3,
+}
+
+// How an entity's behaviours are combined.
+#[derive(Component, Clone, Copy, Debug)]
+pub enum SteeringMode {
+    // Add up all the weighted forces.
+    Blend,
+    // Take weighted forces in priority order until `max_force` is used up,
+    // so that urgent ones can't be cancelled out by the rest.
+    Prioritised { max_force: f32 },
+}
+
+impl Default for SteeringMode {
+    fn default() -> Self {
+        SteeringMode::Blend
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Behaviour {
+    Separation,
+    Alignment,
+    Cohesion,
+    Seek,
+    Flee,
+    Arrive,
+    Pursue,
+    Evade,
+    Wander,
+    PathFollow,
+    LeaderFollow,
+    ObstacleAvoid,
+    ColliderAvoid,
+    Altitude,
+}
+
+// The order prioritised steering takes behaviours in, most urgent first.
+// Anything left out goes last.
+pub struct SteeringPriorities(pub Vec<Behaviour>);
+
+impl Default for SteeringPriorities {
+    fn default() -> Self {
+        SteeringPriorities(vec![
+            Behaviour::ColliderAvoid,
+            Behaviour::ObstacleAvoid,
+            Behaviour::Evade,
+            Behaviour::Flee,
+            Behaviour::Separation,
+            Behaviour::Altitude,
+            Behaviour::Pursue,
+            Behaviour::Seek,
+            Behaviour::Arrive,
+            Behaviour::PathFollow,
+            Behaviour::LeaderFollow,
+            Behaviour::Alignment,
+            Behaviour::Cohesion,
+            Behaviour::Wander,
+        ])
+    }
+}
+
+impl SteeringPriorities {
+    fn rank(&self, behaviour: Behaviour) -> usize
+    {
+        self.0.iter().position(|b| *b == behaviour).unwrap_or(self.0.len())
+    }
+}
+
+/// Combine weighted forces according to `mode`. Reorders `forces` when prioritising.
+pub fn combine(forces: &mut [(Behaviour, Vec3)], mode: SteeringMode, priorities: &SteeringPriorities) -> Vec3
+{
+    match mode {
+        SteeringMode::Blend => forces.iter().fold(Vec3::ZERO, |total, (_, force)| total + *force),
+        SteeringMode::Prioritised { max_force } => {
+            forces.sort_by_key(|(behaviour, _)| priorities.rank(*behaviour));
+
+            let mut total = Vec3::ZERO;
+            let mut remaining = max_force;
+            for (_, force) in forces.iter() {
+                if remaining <= 0. { break; }
+
+                let size = force.length();
+                if size <= remaining {
+                    total += *force;
+                    remaining -= size;
+                } else {
+                    total += *force / size * remaining;
+                    remaining = 0.;
+                }
+            }
+            total
+        }
+    }
+}
+
+/*
+ * The steering maths. Each returns a change of velocity we'd like,
+ * given where we are and how we're moving.
+ */
+
+pub fn seek(offset_to_target: Vec3, velocity: Vec3, max_speed: f32) -> Vec3
+{
+    offset_to_target.normalize_or_zero() * max_speed - velocity
+}
+
+pub fn flee(offset_to_threat: Vec3, velocity: Vec3, max_speed: f32) -> Vec3
+{
+    -offset_to_threat.normalize_or_zero() * max_speed - velocity
+}
+
+// Like seek, but slowing down inside `slowing_radius` so we stop on the target.
+pub fn arrive(offset_to_target: Vec3, velocity: Vec3, max_speed: f32, slowing_radius: f32) -> Vec3
+{
+    let distance = offset_to_target.length();
+    if distance <= 0. { return -velocity; }
+
+    let speed = if slowing_radius > 0. && distance < slowing_radius {
+        max_speed * distance / slowing_radius
+    } else {
+        max_speed
+    };
+    offset_to_target / distance * speed - velocity
+}
+
+// How far ahead to guess where something moving will be.
+fn look_ahead_time(distance: f32, max_speed: f32) -> f32
+{
+    if max_speed > 0. { distance / max_speed } else { 0. }
+}
+
+// Steer sideways away from the nearest obstacle in our path, if any.
+// Obstacles are (offset to centre, radius).
+pub fn avoid(obstacles: impl Iterator<Item = (Vec3, f32)>, velocity: Vec3, max_speed: f32, look_ahead: f32, clearance: f32) -> Vec3
+{
+    let heading = velocity.normalize_or_zero();
+    if heading == Vec3::ZERO { return Vec3::ZERO; }
+
+    let mut nearest: Option<(f32, Vec3)> = None;
+    for (offset, radius) in obstacles {
+        let along = offset.dot(heading);
+        if along < 0. || along > look_ahead + radius { continue; }
+
+        let lateral = offset - heading * along;
+        if lateral.length() >= radius + clearance { continue; }
+
+        if nearest.map_or(true, |(best, _)| along < best) {
+            nearest = Some((along, lateral));
+        }
+    }
+
+    match nearest {
+        Some((along, lateral)) => {
+            // Dead ahead: pick a side.
+            let away = if lateral.length_squared() > 0.0001 {
+                -lateral.normalize()
+            } else {
+                let side = heading.cross(Vec3::Y);
+                if side.length_squared() > 0.0001 { side.normalize() } else { heading.cross(Vec3::X).normalize() }
+            };
+            let urgency = 1.0 - (along / (look_ahead + 0.0001)).clamp(0.0, 1.0);
+            away * max_speed * urgency
+        }
+        None => Vec3::ZERO,
+    }
+}
+
+fn offset_between(wrap: &Option<Wrap>, from: Vec3, to: Vec3) -> Vec3
+{
+    match wrap {
+        Some(wrap) => wrap.offset(from, to),
+        None => to - from,
+    }
+}
+
+/*
+ * Behaviours
+ */
+
+#[derive(Component, Debug)]
+pub struct Seek {
+    pub target: Vec3,
+    pub weight: f32,
+    pub seek_factor: Vec3,
+}
+
+fn seek_system(
+    bounds: Option<Res<Bounds>>,
+    mut query: Query<(&mut Seek, &Transform, &Velocitator)>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut seek_us, transform, velocitator) in query.iter_mut() {
+        let offset = offset_between(&wrap, transform.translation, seek_us.target);
+        seek_us.seek_factor = seek(offset, velocitator.velocity, velocitator.max_speed);
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct Flee {
+    pub target: Vec3,
+    // Only bother fleeing when it's this close.
+    pub panic_distance: f32,
+    pub weight: f32,
+    pub flee_factor: Vec3,
+}
+
+fn flee_system(
+    bounds: Option<Res<Bounds>>,
+    mut query: Query<(&mut Flee, &Transform, &Velocitator)>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut flee_us, transform, velocitator) in query.iter_mut() {
+        let offset = offset_between(&wrap, transform.translation, flee_us.target);
+        flee_us.flee_factor = if offset.length() < flee_us.panic_distance {
+            flee(offset, velocitator.velocity, velocitator.max_speed)
+        } else {
+            Vec3::ZERO
+        };
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct Arrive {
+    pub target: Vec3,
+    pub slowing_radius: f32,
+    pub weight: f32,
+    pub arrive_factor: Vec3,
+}
+
+fn arrive_system(
+    bounds: Option<Res<Bounds>>,
+    mut query: Query<(&mut Arrive, &Transform, &Velocitator)>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut arrive_us, transform, velocitator) in query.iter_mut() {
+        let offset = offset_between(&wrap, transform.translation, arrive_us.target);
+        arrive_us.arrive_factor = arrive(offset, velocitator.velocity, velocitator.max_speed, arrive_us.slowing_radius);
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct Pursue {
+    pub target: Entity,
+    pub weight: f32,
+    pub pursue_factor: Vec3,
+}
+
+fn pursue_system(
+    bounds: Option<Res<Bounds>>,
+    mut query: Query<(&mut Pursue, &Transform, &Velocitator)>,
+    targets: Query<(&Transform, Option<&Velocitator>)>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut pursue_us, transform, velocitator) in query.iter_mut() {
+        pursue_us.pursue_factor = match targets.get(pursue_us.target) {
+            Ok((target_transform, target_velocitator)) => {
+                let offset = offset_between(&wrap, transform.translation, target_transform.translation);
+                let target_velocity = target_velocitator.map_or(Vec3::ZERO, |v| v.velocity);
+                let ahead = look_ahead_time(offset.length(), velocitator.max_speed);
+                seek(offset + target_velocity * ahead, velocitator.velocity, velocitator.max_speed)
+            }
+            Err(_) => Vec3::ZERO,
+        };
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct Evade {
+    pub target: Entity,
+    pub panic_distance: f32,
+    pub weight: f32,
+    pub evade_factor: Vec3,
+}
+
+fn evade_system(
+    bounds: Option<Res<Bounds>>,
+    mut query: Query<(&mut Evade, &Transform, &Velocitator)>,
+    targets: Query<(&Transform, Option<&Velocitator>)>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut evade_us, transform, velocitator) in query.iter_mut() {
+        evade_us.evade_factor = match targets.get(evade_us.target) {
+            Ok((target_transform, target_velocitator)) => {
+                let offset = offset_between(&wrap, transform.translation, target_transform.translation);
+                if offset.length() < evade_us.panic_distance {
+                    let target_velocity = target_velocitator.map_or(Vec3::ZERO, |v| v.velocity);
+                    let ahead = look_ahead_time(offset.length(), velocitator.max_speed);
+                    flee(offset + target_velocity * ahead, velocitator.velocity, velocitator.max_speed)
+                } else {
+                    Vec3::ZERO
+                }
+            }
+            Err(_) => Vec3::ZERO,
+        };
+    }
+}
+
+// Meanders by chasing a point that drifts around a sphere out in front.
+#[derive(Component, Debug)]
+pub struct Wander {
+    // The sphere's size and how far ahead it sits.
+    pub radius: f32,
+    pub distance: f32,
+    // How far the point can drift per second.
+    pub jitter: f32,
+    pub weight: f32,
+    pub wander_target: Vec3,
+    pub wander_factor: Vec3,
+}
+
+fn wander_system(
+    time: Res<Time>,
+    mut query: Query<(&mut Wander, &Velocitator)>,
+)
+{
+    let mut rng = rand::thread_rng();
+    for (mut wander, velocitator) in query.iter_mut() {
+        let jitter = wander.jitter * time.delta_seconds();
+        let nudge = Vec3::new(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+        ) * jitter;
+
+        let on_sphere = (wander.wander_target + nudge).normalize_or_zero() * wander.radius;
+        wander.wander_target = on_sphere;
+
+        let heading = velocitator.velocity.normalize_or_zero();
+        let offset = heading * wander.distance + on_sphere;
+        wander.wander_factor = seek(offset, velocitator.velocity, velocitator.max_speed);
+    }
+}
+
+#[derive(Component, Debug)]
+pub struct PathFollow {
+    pub points: Vec<Vec3>,
+    // How close counts as having reached a point.
+    pub arrive_radius: f32,
+    pub looped: bool,
+    pub current: usize,
+    pub weight: f32,
+    pub path_factor: Vec3,
+}
+
+fn path_follow_system(
+    bounds: Option<Res<Bounds>>,
+    mut query: Query<(&mut PathFollow, &Transform, &Velocitator)>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut path, transform, velocitator) in query.iter_mut() {
+        if path.points.is_empty() {
+            path.path_factor = Vec3::ZERO;
+            continue;
+        }
+
+        let last = path.points.len() - 1;
+        path.current = path.current.min(last);
+
+        let mut offset = offset_between(&wrap, transform.translation, path.points[path.current]);
+        if offset.length() < path.arrive_radius && (path.current < last || path.looped) {
+            path.current = if path.current < last { path.current + 1 } else { 0 };
+            offset = offset_between(&wrap, transform.translation, path.points[path.current]);
+        }
+
+        path.path_factor = if path.current == last && !path.looped {
+            arrive(offset, velocitator.velocity, velocitator.max_speed, path.arrive_radius)
+        } else {
+            seek(offset, velocitator.velocity, velocitator.max_speed)
+        };
+    }
+}
+
+// Tags along a little way behind a leader.
+#[derive(Component, Debug)]
+pub struct LeaderFollow {
+    pub leader: Entity,
+    pub behind: f32,
+    pub slowing_radius: f32,
+    pub weight: f32,
+    pub leader_follow_factor: Vec3,
+}
+
+fn leader_follow_system(
+    bounds: Option<Res<Bounds>>,
+    mut query: Query<(&mut LeaderFollow, &Transform, &Velocitator)>,
+    leaders: Query<(&Transform, Option<&Velocitator>)>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut follow, transform, velocitator) in query.iter_mut() {
+        follow.leader_follow_factor = match leaders.get(follow.leader) {
+            Ok((leader_transform, leader_velocitator)) => {
+                let leader_heading = leader_velocitator.map_or(Vec3::ZERO, |v| v.velocity.normalize_or_zero());
+                let spot = leader_transform.translation - leader_heading * follow.behind;
+                let offset = offset_between(&wrap, transform.translation, spot);
+                arrive(offset, velocitator.velocity, velocitator.max_speed, follow.slowing_radius)
+            }
+            Err(_) => Vec3::ZERO,
+        };
+    }
+}
+
+// A sphere to keep out of.
+#[derive(Component, Debug)]
+pub struct Obstacle {
+    pub radius: f32,
+}
+
+#[derive(Component, Debug)]
+pub struct ObstacleAvoid {
+    // How far ahead we look, and how much room we want to leave.
+    pub look_ahead: f32,
+    pub clearance: f32,
+    pub weight: f32,
+    pub avoid_factor: Vec3,
+}
+
+fn obstacle_avoid_system(
+    bounds: Option<Res<Bounds>>,
+    mut query: Query<(&mut ObstacleAvoid, &Transform, &Velocitator)>,
+    obstacles: Query<(&Transform, &Obstacle)>,
+)
+{
+    let wrap = bounds.map(|bounds| bounds.wrap());
+    for (mut avoid_us, transform, velocitator) in query.iter_mut() {
+        let nearby = obstacles.iter().map(|(obstacle_transform, obstacle)| {
+            (offset_between(&wrap, transform.translation, obstacle_transform.translation), obstacle.radius)
+        });
+        avoid_us.avoid_factor = avoid(nearby, velocitator.velocity, velocitator.max_speed, avoid_us.look_ahead, avoid_us.clearance);
+    }
+}
+
+// Anything with a behaviour gets somewhere to put the sum.
+fn steering_insert_system(
+    mut commands: Commands,
+    query: Query<Entity, (Without<Steering>, Or<(
+        Or<(With<Separation>, With<Alignment>, With<Cohesion>)>,
+        Or<(With<Seek>, With<Flee>, With<Arrive>)>,
+        Or<(With<Pursue>, With<Evade>, With<Wander>)>,
+        Or<(With<PathFollow>, With<LeaderFollow>, With<ObstacleAvoid>)>,
+        Or<(With<ColliderAvoid>, With<KeepAltitude>)>,
+    )>)>,
+)
+{
+    for entity in query.iter() {
+        commands.entity(entity).insert(Steering::default());
+    }
+}
+
+fn steering_sum_system(
+    global: Option<Res<FlockingProfile>>,
+    per_species: Option<Res<SpeciesFlockingProfiles>>,
+    priorities: Res<SteeringPriorities>,
+    mut query: Query<(
+        &mut Steering,
+        Option<&SteeringMode>,
+        (Option<&Separation>, Option<&Alignment>, Option<&Cohesion>),
+        (Option<&FlockingProfile>, Option<&Species>),
+        (Option<&Seek>, Option<&Flee>, Option<&Arrive>),
+        (Option<&Pursue>, Option<&Evade>, Option<&Wander>),
+        (Option<&PathFollow>, Option<&LeaderFollow>, Option<&ObstacleAvoid>),
+        (Option<&ColliderAvoid>, Option<&KeepAltitude>),
+    )>,
+)
+{
+    // JayBoids' profiles if it's there.
+    let global = global.as_deref();
+    let per_species = per_species.as_deref();
+
+    let mut forces = Vec::new();
+    for (mut steering, mode, (separation, alignment, cohesion), (own, species), (seek_us, flee_us, arrive_us), (pursue, evade, wander), (path, follow, avoid_us), (avoid_colliders, keep_altitude)) in query.iter_mut() {
+        forces.clear();
+
+        let profile = resolve_profile_or_default(global, per_species, own, species);
+        let (separate, align, cohere) = weighted_rules(&profile, separation, alignment, cohesion);
+        forces.push((Behaviour::Separation, separate));
+        forces.push((Behaviour::Alignment, align));
+        forces.push((Behaviour::Cohesion, cohere));
+
+        if let Some(b) = seek_us { forces.push((Behaviour::Seek, b.seek_factor * b.weight)); }
+        if let Some(b) = flee_us { forces.push((Behaviour::Flee, b.flee_factor * b.weight)); }
+        if let Some(b) = arrive_us { forces.push((Behaviour::Arrive, b.arrive_factor * b.weight)); }
+        if let Some(b) = pursue { forces.push((Behaviour::Pursue, b.pursue_factor * b.weight)); }
+        if let Some(b) = evade { forces.push((Behaviour::Evade, b.evade_factor * b.weight)); }
+        if let Some(b) = wander { forces.push((Behaviour::Wander, b.wander_factor * b.weight)); }
+        if let Some(b) = path { forces.push((Behaviour::PathFollow, b.path_factor * b.weight)); }
+        if let Some(b) = follow { forces.push((Behaviour::LeaderFollow, b.leader_follow_factor * b.weight)); }
+        if let Some(b) = avoid_us { forces.push((Behaviour::ObstacleAvoid, b.avoid_factor * b.weight)); }
+        if let Some(b) = avoid_colliders { forces.push((Behaviour::ColliderAvoid, b.collider_avoid_factor * b.weight)); }
+        if let Some(b) = keep_altitude { forces.push((Behaviour::Altitude, b.altitude_factor * b.weight)); }
+
+        steering.force = combine(&mut forces, mode.copied().unwrap_or_default(), &priorities);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seek_heads_for_target_at_full_speed() {
+        let change = seek(Vec3::new(10.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0), 5.0);
+        assert_eq!(change, Vec3::new(5.0, 0.0, -2.0));
+    }
+
+    #[test]
+    fn flee_heads_away() {
+        let change = flee(Vec3::new(10.0, 0.0, 0.0), Vec3::ZERO, 5.0);
+        assert_eq!(change, Vec3::new(-5.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn arrive_slows_down_near_target() {
+        let far = arrive(Vec3::new(100.0, 0.0, 0.0), Vec3::ZERO, 10.0, 20.0);
+        let near = arrive(Vec3::new(5.0, 0.0, 0.0), Vec3::ZERO, 10.0, 20.0);
+        assert_eq!(far, Vec3::new(10.0, 0.0, 0.0));
+        assert_eq!(near, Vec3::new(2.5, 0.0, 0.0));
+    }
+
+    #[test]
+    fn blend_adds_everything() {
+        let mut forces = vec![(Behaviour::Cohesion, Vec3::new(5.0, 0.0, 0.0)), (Behaviour::ObstacleAvoid, Vec3::new(-5.0, 0.0, 0.0))];
+        let total = combine(&mut forces, SteeringMode::Blend, &SteeringPriorities::default());
+        assert_eq!(total, Vec3::ZERO);
+    }
+
+    #[test]
+    fn prioritised_spends_budget_on_urgent_forces_first() {
+        let mut forces = vec![
+            (Behaviour::Cohesion, Vec3::new(5.0, 0.0, 0.0)),
+            (Behaviour::Alignment, Vec3::new(0.0, 0.0, 4.0)),
+            (Behaviour::ObstacleAvoid, Vec3::new(-3.0, 0.0, 0.0)),
+        ];
+        let total = combine(&mut forces, SteeringMode::Prioritised { max_force: 5.0 }, &SteeringPriorities::default());
+
+        // Avoidance gets all it wants, alignment the rest, cohesion nothing.
+        assert!((total - Vec3::new(-3.0, 0.0, 2.0)).length() < 1e-5);
+    }
+
+    #[test]
+    fn avoid_pushes_away_from_obstacle_in_path() {
+        let obstacles = vec![(Vec3::new(0.5, 0.0, -10.0), 2.0)];
+        let change = avoid(obstacles.into_iter(), Vec3::new(0.0, 0.0, -5.0), 5.0, 20.0, 0.5);
+        assert!(change.x < 0.0);
+
+        // Behind us or off to the side doesn't matter.
+        let obstacles = vec![(Vec3::new(0.0, 0.0, 10.0), 2.0), (Vec3::new(10.0, 0.0, -10.0), 2.0)];
+        let change = avoid(obstacles.into_iter(), Vec3::new(0.0, 0.0, -5.0), 5.0, 20.0, 0.5);
+        assert_eq!(change, Vec3::ZERO);
+    }
+}
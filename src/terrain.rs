@@ -0,0 +1,90 @@
+use bevy::{
+    prelude::*,
+};
+
+use crate::bounds;
+use bounds::*;
+
+// Ground heights on a regular x/z grid, for things that need to know how
+// high the ground is without casting rays at it.
+pub struct HeightField {
+    origin: Vec2,
+    cell_size: f32,
+    cells_x: usize,
+    cells_z: usize,
+    // One more sample than cells along each side, x first.
+    heights: Vec<f32>,
+}
+
+impl HeightField {
+    pub fn new(origin_x: f32, origin_z: f32, cell_size: f32, cells_x: usize, cells_z: usize) -> HeightField {
+        HeightField {
+            origin: Vec2::new(origin_x, origin_z),
+            cell_size,
+            cells_x,
+            cells_z,
+            heights: vec![0.0; (cells_x + 1) * (cells_z + 1)],
+        }
+    }
+
+    /// Level ground at `height` covering the bounds.
+    pub fn flat(bounds: &Bounds, height: f32) -> HeightField {
+        let mut field = HeightField::new(bounds.x_min, bounds.z_min, bounds.cell_size, bounds.cells_x.max(1), bounds.cells_z.max(1));
+        for h in field.heights.iter_mut() {
+            *h = height;
+        }
+        field
+    }
+
+    /// Set the sample at grid corner (x, z).
+    pub fn set(&mut self, x: usize, z: usize, height: f32)
+    {
+        if x <= self.cells_x && z <= self.cells_z {
+            self.heights[x + z * (self.cells_x + 1)] = height;
+        }
+    }
+
+    fn sample(&self, x: usize, z: usize) -> f32
+    {
+        self.heights[x.min(self.cells_x) + z.min(self.cells_z) * (self.cells_x + 1)]
+    }
+
+    /// Ground height at a world x/z, blended between the nearest samples.
+    /// Off the edge of the grid it carries on at the edge's height.
+    pub fn height_at(&self, x: f32, z: f32) -> f32
+    {
+        if self.cell_size <= 0. { return self.heights[0]; }
+
+        let gx = ((x - self.origin.x) / self.cell_size).clamp(0.0, self.cells_x as f32);
+        let gz = ((z - self.origin.y) / self.cell_size).clamp(0.0, self.cells_z as f32);
+        let (x0, z0) = (gx.floor() as usize, gz.floor() as usize);
+        let (tx, tz) = (gx - x0 as f32, gz - z0 as f32);
+
+        let near = self.sample(x0, z0) * (1.0 - tx) + self.sample(x0 + 1, z0) * tx;
+        let far = self.sample(x0, z0 + 1) * (1.0 - tx) + self.sample(x0 + 1, z0 + 1) * tx;
+        near * (1.0 - tz) + far * tz
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_is_flat_everywhere() {
+        let field = HeightField::flat(&Bounds::new(10., 0., 100., 0., 50., 0., 100., 0.), 3.0);
+        assert_eq!(field.height_at(55.0, 12.0), 3.0);
+        assert_eq!(field.height_at(-500.0, 5000.0), 3.0);
+    }
+
+    #[test]
+    fn heights_blend_between_samples() {
+        let mut field = HeightField::new(0., 0., 10., 2, 2);
+        field.set(1, 1, 8.0);
+
+        assert_eq!(field.height_at(10.0, 10.0), 8.0);
+        assert_eq!(field.height_at(5.0, 10.0), 4.0);
+        assert_eq!(field.height_at(5.0, 5.0), 2.0);
+        assert_eq!(field.height_at(20.0, 20.0), 0.0);
+    }
+}
@@ -8,6 +8,12 @@ use crate::boids;
 use boids::*;
 use crate::bounds;
 use bounds::*;
+use crate::steering;
+use steering::*;
+use crate::flight;
+use flight::*;
+use crate::wind;
+use wind::*;
 
 // Our own plugin:
 pub struct JayVelocitate;
@@ -15,20 +21,39 @@ pub struct JayVelocitate;
 impl Plugin for JayVelocitate {
     fn build(&self, app: &mut App) {
         app
-            .add_system(velocitator_update_system)
+            .add_system(velocitator_update_system
+                .label(VelocitateSystem::Update)
+                .after(BoidsSystem::Rules))
             .add_system(velocitator_limit_system.after(velocitator_update_system))
             .add_system(velocitate_system.after(velocitator_limit_system))
             .add_system(orient_to_velocity_system.after(velocitate_system))
-            .add_system(keep_in_bounds_system.after(orient_to_velocity_system));
+            .add_system(keep_in_bounds_system.after(orient_to_velocity_system))
+            .add_system(velocitator_to_flyer_system
+                .after(keep_in_bounds_system)
+                .before(FlightSystem::Goals))
+            .add_system(flyer_to_velocitator_system.after(FlightSystem::Movement))
+            .add_system(flyer_wrap_system.after(FlightSystem::Movement));
     }
 }
 
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum VelocitateSystem {
+    Update,
+}
+
 #[derive(Component, Debug)]
 pub struct Velocitator {
     pub velocity: Vec3,
     pub max_speed: f32,
 }
 
+// Fly with the Flight model instead: the velocity, once steered, becomes the
+// goal for the entity's Flyer, which gets there within its own turn and
+// acceleration limits. After the Flyer moves, the velocity is set back to how
+// it's really flying, so steering always starts from there. Needs the Flight plugin.
+#[derive(Component, Debug, Default)]
+pub struct SteersFlyer;
+
 fn velocitator_limit_system(
     mut query: Query<&mut Velocitator>,
 ) {
@@ -40,30 +65,52 @@ fn velocitator_limit_system(
     }
 }
 
+// The velocity is through the air, so any wind carries us along on top.
 fn velocitate_system(
     time: Res<Time>,
-    mut query: Query<(&mut Transform, &Velocitator)>,
+    bounds: Res<Bounds>,
+    wind: Option<Res<Wind>>,
+    mut query: Query<(&mut Transform, &Velocitator), Without<SteersFlyer>>,
 ) {
+    let wrap = bounds.wrap();
     for (mut transform, velocitator) in query.iter_mut() {
-        transform.translation += velocitator.velocity * time.delta().as_secs_f32();
+        let drift = wind.as_ref().map_or(Vec3::ZERO, |wind| wind.at(transform.translation));
+        let moved = transform.translation + (velocitator.velocity + drift) * time.delta().as_secs_f32();
+        transform.translation = wrap.position(moved);
     }
 }
 
+// Steer by whatever JaySteering has combined, or without it just by the boids rules.
 fn velocitator_update_system(
     time: Res<Time>,
-    mut query: Query<(&mut Velocitator, &Separation, &Alignment, &Cohesion)>,
+    global: Option<Res<FlockingProfile>>,
+    per_species: Option<Res<SpeciesFlockingProfiles>>,
+    mut query: Query<(
+        &mut Velocitator,
+        Option<&Steering>,
+        (Option<&Separation>, Option<&Alignment>, Option<&Cohesion>),
+        (Option<&FlockingProfile>, Option<&Species>),
+    )>,
 )
 {
-    for (mut velocitator, separation, alignment, cohesion) in query.iter_mut() {
-        velocitator.velocity += time.delta().as_secs_f32() *
-            (separation.separation_factor * 0.4
-                + alignment.alignment_factor * 0.4
-                + cohesion.cohesion_factor * 0.04);
+    let global = global.as_deref();
+    let per_species = per_species.as_deref();
+
+    for (mut velocitator, steering, (separation, alignment, cohesion), (own, species)) in query.iter_mut() {
+        let force = match steering {
+            Some(steering) => steering.force,
+            None => {
+                let profile = resolve_profile_or_default(global, per_species, own, species);
+                let (separate, align, cohere) = weighted_rules(&profile, separation, alignment, cohesion);
+                separate + align + cohere
+            }
+        };
+        velocitator.velocity += time.delta().as_secs_f32() * force;
     }
 }
 
 fn orient_to_velocity_system(
-    mut query: Query<(&mut Transform, &Velocitator)>,
+    mut query: Query<(&mut Transform, &Velocitator), Without<SteersFlyer>>,
 )
 {
     for (mut transform, velocitator) in query.iter_mut() {
@@ -72,6 +119,44 @@ fn orient_to_velocity_system(
     }
 }
 
+// How the Flyer actually moved through the air this frame (leaving out any
+// drift, as velocities are airspeeds), for steering from next frame.
+fn flyer_to_velocitator_system(
+    mut query: Query<(&mut Velocitator, &Flyer, &Transform), With<SteersFlyer>>,
+)
+{
+    for (mut velocitator, flyer, transform) in query.iter_mut() {
+        velocitator.velocity = transform.forward() * flyer.speed_linear + Vec3::Y * flyer.speed_vertical;
+    }
+}
+
+// The velocity is through the air but the Flyer's goal is over the ground,
+// so add the wind in; the Flight model takes it back off when it heads for it.
+fn velocitator_to_flyer_system(
+    wind: Option<Res<Wind>>,
+    mut query: Query<(&Velocitator, &Transform, &mut FlyerGoalVelocity), With<SteersFlyer>>,
+)
+{
+    for (velocitator, transform, mut goal) in query.iter_mut() {
+        let drift = wind.as_ref().map_or(Vec3::ZERO, |wind| wind.at(transform.translation));
+        goal.velocity = velocitator.velocity + drift;
+    }
+}
+
+// The Flight model doesn't know about Bounds, so wrap flyers around here.
+fn flyer_wrap_system(
+    bounds: Res<Bounds>,
+    mut query: Query<&mut Transform, With<SteersFlyer>>,
+)
+{
+    let wrap = bounds.wrap();
+    if !wrap.any() { return; }
+
+    for mut transform in query.iter_mut() {
+        transform.translation = wrap.position(transform.translation);
+    }
+}
+
 fn keep_in_bounds_system(
     mut query: Query<(&Transform, &mut Velocitator)>,
     bounds: Res<Bounds>,
@@ -80,24 +165,30 @@ fn keep_in_bounds_system(
     for (transform, mut velocitator) in query.iter_mut() {
         let turn_factor = 1.;
 
-
-        if transform.translation.x < bounds.x_min + bounds.margin {
-            velocitator.velocity.x += turn_factor;
-        }
-        if transform.translation.x > bounds.x_max - bounds.margin {
-            velocitator.velocity.x -= turn_factor
-        }
-        if transform.translation.y < bounds.y_min + bounds.margin {
-            velocitator.velocity.y += turn_factor;
-        }
-        if transform.translation.y > bounds.y_max - bounds.margin {
-            velocitator.velocity.y -= turn_factor;
+        // No walls along axes that wrap around.
+        if !bounds.wrap_x {
+            if transform.translation.x < bounds.x_min + bounds.margin {
+                velocitator.velocity.x += turn_factor;
+            }
+            if transform.translation.x > bounds.x_max - bounds.margin {
+                velocitator.velocity.x -= turn_factor
+            }
         }
-        if transform.translation.z < bounds.z_min + bounds.margin {
-            velocitator.velocity.z += turn_factor;
+        if !bounds.wrap_y {
+            if transform.translation.y < bounds.y_min + bounds.margin {
+                velocitator.velocity.y += turn_factor;
+            }
+            if transform.translation.y > bounds.y_max - bounds.margin {
+                velocitator.velocity.y -= turn_factor;
+            }
         }
-        if transform.translation.z > bounds.z_max - bounds.margin {
-            velocitator.velocity.z -= turn_factor;
+        if !bounds.wrap_z {
+            if transform.translation.z < bounds.z_min + bounds.margin {
+                velocitator.velocity.z += turn_factor;
+            }
+            if transform.translation.z > bounds.z_max - bounds.margin {
+                velocitator.velocity.z -= turn_factor;
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,186 @@
+use std::f32::consts::PI;
+use bevy::{
+    prelude::*,
+};
+use rand::prelude::*;
+
+// Our own plugin. Keeps the Wind's turbulence and gusts moving along;
+// Velocitate and Flight blow things about with it if it's there.
+pub struct JayWind;
+
+impl Plugin for JayWind {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<Wind>()
+            .add_system(wind_update_system.label(WindSystem::Update));
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WindSystem {
+    Update,
+}
+
+// A steady wind everywhere, swirling turbulence that varies from place to
+// place, and the odd gust.
+pub struct Wind {
+    pub global: Vec3,
+    pub turbulence_strength: f32,
+    // Roughly how far apart the swirls are.
+    pub turbulence_scale: f32,
+    // How quickly the swirls change.
+    pub turbulence_speed: f32,
+    pub gust_strength: f32,
+    pub gust_seconds: f32,
+    // Time between gusts is picked at random from this range.
+    pub gust_interval_min: f32,
+    pub gust_interval_max: f32,
+    time: f32,
+    gust: Vec3,
+    // Counts down to the next gust, then through the gust itself.
+    gust_timer: f32,
+    gusting: bool,
+}
+
+impl Default for Wind {
+    fn default() -> Self {
+        Wind {
+            global: Vec3::ZERO,
+            turbulence_strength: 0.0,
+            turbulence_scale: 200.0,
+            turbulence_speed: 0.1,
+            gust_strength: 0.0,
+            gust_seconds: 3.0,
+            gust_interval_min: 5.0,
+            gust_interval_max: 20.0,
+            time: 0.0,
+            gust: Vec3::ZERO,
+            gust_timer: 0.0,
+            gusting: false,
+        }
+    }
+}
+
+impl Wind {
+    /// The wind at a position.
+    pub fn at(&self, pos: Vec3) -> Vec3
+    {
+        self.global + self.turbulence(pos) + self.current_gust()
+    }
+
+    /// The swirling part. It's the curl of a smooth field, so it doesn't pile
+    /// things up or thin them out anywhere.
+    pub fn turbulence(&self, pos: Vec3) -> Vec3
+    {
+        if self.turbulence_strength == 0. || self.turbulence_scale <= 0. { return Vec3::ZERO; }
+
+        let k = 2.0 * PI / self.turbulence_scale;
+        let t = self.time * self.turbulence_speed;
+        let (su, cu) = (k * pos.x + t * 0.7).sin_cos();
+        let (sv, cv) = (k * pos.y + t * 1.1).sin_cos();
+        let (sw, cw) = (k * pos.z + t * 0.9).sin_cos();
+
+        // Curl of (sin v cos w, sin w cos u, sin u cos v).
+        -Vec3::new(
+            su * sv + cw * cu,
+            sv * sw + cu * cv,
+            sw * su + cv * cw,
+        ) * (0.5 * self.turbulence_strength)
+    }
+
+    fn current_gust(&self) -> Vec3
+    {
+        if !self.gusting || self.gust_seconds <= 0. { return Vec3::ZERO; }
+
+        // Builds up and dies away.
+        self.gust * (PI * (1.0 - self.gust_timer / self.gust_seconds)).sin()
+    }
+
+    /// Move the wind on by `delta_time`.
+    pub fn advance(&mut self, delta_time: f32, rng: &mut impl Rng)
+    {
+        self.time += delta_time;
+
+        if self.gust_strength <= 0. {
+            self.gusting = false;
+            return;
+        }
+
+        self.gust_timer -= delta_time;
+        if self.gust_timer > 0. { return; }
+
+        if self.gusting {
+            self.gusting = false;
+            self.gust_timer = rng.gen_range(self.gust_interval_min..=self.gust_interval_max.max(self.gust_interval_min));
+        } else {
+            let angle = rng.gen_range(0.0..2.0 * PI);
+            self.gust = Vec3::new(angle.cos(), 0.0, angle.sin()) * self.gust_strength;
+            self.gusting = true;
+            self.gust_timer = self.gust_seconds;
+        }
+    }
+}
+
+fn wind_update_system(
+    time: Res<Time>,
+    mut wind: ResMut<Wind>,
+)
+{
+    wind.advance(time.delta_seconds(), &mut rand::thread_rng());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turbulent() -> Wind {
+        Wind {
+            turbulence_strength: 3.0,
+            turbulence_scale: 50.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn calm_by_default() {
+        let wind = Wind::default();
+        assert_eq!(wind.at(Vec3::new(10.0, 20.0, 30.0)), Vec3::ZERO);
+    }
+
+    #[test]
+    fn turbulence_varies_but_is_divergence_free() {
+        let wind = turbulent();
+        let mut rng = StdRng::seed_from_u64(3);
+        let h = 0.01;
+        for _ in 0..100 {
+            let p = Vec3::new(rng.gen_range(-100.0..100.0), rng.gen_range(-100.0..100.0), rng.gen_range(-100.0..100.0));
+            let dx = (wind.turbulence(p + Vec3::X * h).x - wind.turbulence(p - Vec3::X * h).x) / (2.0 * h);
+            let dy = (wind.turbulence(p + Vec3::Y * h).y - wind.turbulence(p - Vec3::Y * h).y) / (2.0 * h);
+            let dz = (wind.turbulence(p + Vec3::Z * h).z - wind.turbulence(p - Vec3::Z * h).z) / (2.0 * h);
+            assert!((dx + dy + dz).abs() < 0.01);
+        }
+        assert_ne!(wind.turbulence(Vec3::ZERO), wind.turbulence(Vec3::splat(12.0)));
+    }
+
+    #[test]
+    fn gusts_come_and_go() {
+        let mut wind = Wind {
+            gust_strength: 10.0,
+            gust_seconds: 2.0,
+            gust_interval_min: 5.0,
+            gust_interval_max: 5.0,
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(5);
+
+        wind.advance(0.1, &mut rng);
+        wind.advance(1.0, &mut rng);
+        let peak = wind.at(Vec3::ZERO);
+        assert!((peak.length() - 10.0).abs() < 0.01);
+        assert_eq!(peak.y, 0.0);
+
+        wind.advance(1.0, &mut rng);
+        wind.advance(0.1, &mut rng);
+        assert_eq!(wind.at(Vec3::ZERO), Vec3::ZERO);
+    }
+}